@@ -0,0 +1,364 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use axum::{
+    body::HttpBody,
+    extract::Request,
+    http::{header, HeaderMap, HeaderName, Method, Uri, Version},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use tracing_appender::non_blocking::{NonBlocking, NonBlockingBuilder, WorkerGuard};
+
+use crate::{client_ip::ClientIp, config::AccessLogConfig};
+
+
+
+const COMMON_FORMAT: &str = r#"%h %l %u %t "%r" %>s %b"#;
+const COMBINED_FORMAT: &str = r#"%h %l %u %t "%r" %>s %b "%{Referer}i" "%{User-Agent}i""#;
+
+// maps the preset names to their format strings, anything else is taken as a custom format
+fn resolve_format(format: &str) -> &str {
+    match format {
+        "common" => COMMON_FORMAT,
+        "combined" => COMBINED_FORMAT,
+        custom => custom,
+    }
+}
+
+
+// the parts of the request needed to write an access log line. Captured by `capture_request` and
+// handed to the `TraceLayer` `on_response` hook through the response extensions, as that hook only
+// gets to see the response
+#[derive(Clone)]
+pub(crate) struct RequestInfo {
+    client_ip: Option<IpAddr>,
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    received_at: DateTime<Utc>,
+}
+
+pub(crate) async fn capture_request(request: Request, next: Next) -> Response {
+    let info = RequestInfo {
+        client_ip: request
+            .extensions()
+            .get::<ClientIp>()
+            .and_then(|ClientIp(ip)| *ip),
+        method: request.method().clone(),
+        uri: request.uri().clone(),
+        version: request.version(),
+        headers: request.headers().clone(),
+        received_at: Utc::now(),
+    };
+
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(info);
+    response
+}
+
+
+enum Directive {
+    Literal(String),
+    RemoteHost,
+    RemoteLogname,
+    RemoteUser,
+    Time,
+    RequestLine,
+    Status,
+    BytesClf,
+    Bytes,
+    Microseconds,
+    Seconds,
+    Method,
+    Path,
+    Query,
+    Protocol,
+    RequestHeader(HeaderName),
+    ResponseHeader(HeaderName),
+}
+
+pub(crate) fn validate_format(format: &str) -> Result<(), String> {
+    parse_format(resolve_format(format)).map(|_| ())
+}
+
+// parses the subset of the Apache `LogFormat` directives that make sense for this server
+fn parse_format(format: &str) -> Result<Vec<Directive>, String> {
+    let mut directives = Vec::new();
+    let mut literal = String::new();
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            literal.push(c);
+            continue;
+        }
+        // final status modifiers are accepted for compatibility, there are no internal redirects
+        while matches!(chars.peek(), Some('>') | Some('<')) {
+            chars.next();
+        }
+        let directive = match chars.next() {
+            Some('%') => {
+                literal.push('%');
+                continue;
+            }
+            Some('{') => {
+                let name: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                let name = HeaderName::try_from(name.as_str())
+                    .map_err(|_| format!("invalid header name '{}' in access log format", name))?;
+                match chars.next() {
+                    Some('i') => Directive::RequestHeader(name),
+                    Some('o') => Directive::ResponseHeader(name),
+                    other => return Err(format!("unsupported access log directive '%{{{}}}{}'", name, other.unwrap_or(' '))),
+                }
+            }
+            Some('h') | Some('a') => Directive::RemoteHost,
+            Some('l') => Directive::RemoteLogname,
+            Some('u') => Directive::RemoteUser,
+            Some('t') => Directive::Time,
+            Some('r') => Directive::RequestLine,
+            Some('s') => Directive::Status,
+            Some('b') => Directive::BytesClf,
+            Some('B') => Directive::Bytes,
+            Some('D') => Directive::Microseconds,
+            Some('T') => Directive::Seconds,
+            Some('m') => Directive::Method,
+            Some('U') => Directive::Path,
+            Some('q') => Directive::Query,
+            Some('H') => Directive::Protocol,
+            Some(other) => return Err(format!("unsupported access log directive '%{}'", other)),
+            None => return Err("access log format ends with a dangling '%'".to_string()),
+        };
+        if !literal.is_empty() {
+            directives.push(Directive::Literal(std::mem::take(&mut literal)));
+        }
+        directives.push(directive);
+    }
+    if !literal.is_empty() {
+        directives.push(Directive::Literal(literal));
+    }
+
+    Ok(directives)
+}
+
+// escapes quotes, backslashes and control characters the same way Apache does so a client can't
+// forge extra fields or lines
+fn push_escaped(line: &mut String, value: &[u8]) {
+    for byte in value {
+        match byte {
+            b'"' => line.push_str("\\\""),
+            b'\\' => line.push_str("\\\\"),
+            0x20..=0x7e => line.push(*byte as char),
+            _ => {
+                let _ = write!(line, "\\x{:02x}", byte);
+            }
+        }
+    }
+}
+
+fn push_header(line: &mut String, headers: &HeaderMap, name: &HeaderName) {
+    match headers.get(name) {
+        Some(value) => push_escaped(line, value.as_bytes()),
+        None => line.push('-'),
+    }
+}
+
+
+pub(crate) struct AccessLog {
+    directives: Vec<Directive>,
+    writer: NonBlocking,
+}
+
+impl AccessLog {
+    // the returned guard flushes outstanding lines when dropped so must be held until shutdown
+    pub(crate) fn new(path: &Path, config: &AccessLogConfig) -> Result<(Self, WorkerGuard), String> {
+        let directives = parse_format(resolve_format(&config.format))?;
+        let file = RotatingFile::open(path, config.max_size_mb * 1024 * 1024, config.max_files)
+            .map_err(|err| format!("failed to open access log {}: {}", path.display(), err))?;
+        // lines are written from a dedicated thread so request handling never blocks on disk
+        let (writer, guard) = NonBlockingBuilder::default()
+            .lossy(false)
+            .thread_name("access-log")
+            .finish(file);
+
+        Ok((AccessLog { directives, writer }, guard))
+    }
+
+    pub(crate) fn record(&self, response: &Response, latency: Duration) {
+        let Some(request) = response.extensions().get::<RequestInfo>() else {
+            return;
+        };
+
+        let bytes = response
+            .body()
+            .size_hint()
+            .exact()
+            .or_else(|| {
+                response
+                    .headers()
+                    .get(header::CONTENT_LENGTH)
+                    .and_then(|value| value.to_str().ok()?.parse().ok())
+            });
+
+        let mut line = String::new();
+        for directive in &self.directives {
+            match directive {
+                Directive::Literal(literal) => line.push_str(literal),
+                // the client address as resolved through any trusted proxies, like Apache's mod_remoteip
+                Directive::RemoteHost => match request.client_ip {
+                    Some(ip) => line.push_str(&ip.to_string()),
+                    None => line.push('-'),
+                },
+                Directive::RemoteLogname | Directive::RemoteUser => line.push('-'),
+                Directive::Time => {
+                    let _ = write!(line, "[{}]", request.received_at.format("%d/%b/%Y:%H:%M:%S %z"));
+                }
+                Directive::RequestLine => {
+                    let request_line = format!("{} {} {:?}", request.method, request.uri, request.version);
+                    push_escaped(&mut line, request_line.as_bytes());
+                }
+                Directive::Status => line.push_str(response.status().as_str()),
+                Directive::BytesClf => match bytes {
+                    Some(0) | None => line.push('-'),
+                    Some(bytes) => line.push_str(&bytes.to_string()),
+                },
+                Directive::Bytes => line.push_str(&bytes.unwrap_or_default().to_string()),
+                Directive::Microseconds => line.push_str(&latency.as_micros().to_string()),
+                Directive::Seconds => line.push_str(&latency.as_secs().to_string()),
+                Directive::Method => line.push_str(request.method.as_str()),
+                Directive::Path => push_escaped(&mut line, request.uri.path().as_bytes()),
+                Directive::Query => {
+                    if let Some(query) = request.uri.query() {
+                        line.push('?');
+                        push_escaped(&mut line, query.as_bytes());
+                    }
+                }
+                Directive::Protocol => {
+                    let _ = write!(line, "{:?}", request.version);
+                }
+                Directive::RequestHeader(name) => push_header(&mut line, &request.headers, name),
+                Directive::ResponseHeader(name) => push_header(&mut line, response.headers(), name),
+            }
+        }
+        line.push('\n');
+
+        // a single write per line so the background writer never interleaves partial lines
+        if let Err(err) = self.writer.clone().write_all(line.as_bytes()) {
+            tracing::error!("Failed to write access log line. Error: {:#?}", err);
+        }
+    }
+}
+
+
+// file writer rotating daily and, optionally, once a size limit is reached. Rotated files are
+// renamed to `<name>.<date>` (with a `.<n>` suffix for size rotations within the same day) and the
+// oldest are removed once there are more than `max_files`
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened_on: NaiveDate,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, max_files: usize) -> io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        // an existing file from a previous day is rotated on the first write
+        let opened_on = metadata
+            .modified()
+            .map(|modified| DateTime::<Utc>::from(modified).date_naive())
+            .unwrap_or_else(|_| Utc::now().date_naive());
+
+        Ok(RotatingFile {
+            path: path.to_owned(),
+            file,
+            size: metadata.len(),
+            opened_on,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let base = format!("{}.{}", self.path.display(), self.opened_on.format("%Y-%m-%d"));
+        let mut rotated = PathBuf::from(&base);
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = PathBuf::from(format!("{}.{}", base, n));
+            n += 1;
+        }
+        fs::rename(&self.path, &rotated)?;
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened_on = Utc::now().date_naive();
+
+        self.prune()
+    }
+
+    fn prune(&self) -> io::Result<()> {
+        if self.max_files == 0 {
+            return Ok(());
+        }
+        let Some(file_name) = self.path.file_name().and_then(|name| name.to_str()) else {
+            return Ok(());
+        };
+        let prefix = format!("{}.", file_name);
+        let dir = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+            _ => PathBuf::from("."),
+        };
+
+        let mut rotated: Vec<_> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_str().is_some_and(|name| name.starts_with(&prefix)))
+            .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+            .collect();
+        if rotated.len() <= self.max_files {
+            return Ok(());
+        }
+
+        rotated.sort();
+        for (_, path) in &rotated[..rotated.len() - self.max_files] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let new_day = Utc::now().date_naive() != self.opened_on;
+        let too_big = self.max_size > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_size;
+        if new_day || too_big {
+            // a failed rotation shouldn't lose the line, keep appending to the current file
+            if let Err(err) = self.rotate() {
+                eprintln!("Failed to rotate access log {}. Error: {:#?}", self.path.display(), err);
+            }
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -0,0 +1,812 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+
+use crate::{
+    analytics::write_atomically,
+    config::Config,
+    content::{BlogPost, Content},
+    http_client::HttpClient,
+    http_signatures::{Signature, SigningKey},
+    metrics::metrics,
+    state::AppState,
+};
+
+
+
+const ACTIVITY_JSON: &str = "application/activity+json";
+
+// asked for when fetching actors, some servers only answer the JSON-LD form
+const ACTIVITY_ACCEPT: &str =
+    "application/activity+json, application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"";
+
+const ACTIVITY_STREAMS: &str = "https://www.w3.org/ns/activitystreams";
+const SECURITY: &str = "https://w3id.org/security/v1";
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+// posts served as an `Article`, kept briefly so edits still reach servers that look them up again
+const ARTICLE_CACHE_CONTROL: &str = "public, max-age=300";
+
+// how long the delivery queue sleeps with nothing waiting to be retried, new deliveries wake it
+const IDLE_WAIT: Duration = Duration::from_secs(60 * 60);
+
+// backing off further than this would leave a follower's server behind for days
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(24 * 60 * 60);
+
+// everything kept in `activitypub.path`
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct Stored {
+    // actor id -> follower
+    followers: BTreeMap<String, Follower>,
+    // posts a Create has been queued for. `None` until posts are first delivered, when every post
+    // already published is taken as delivered so that turning federation on doesn't flood followers
+    delivered: Option<BTreeSet<String>>,
+    // deliveries waiting for their next attempt
+    queue: Vec<Delivery>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Follower {
+    inbox: String,
+    // where the follower's server takes activities for all its users at once
+    shared_inbox: Option<String>,
+    followed: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Delivery {
+    inbox: String,
+    activity: Value,
+    attempts: u32,
+    next_attempt: DateTime<Utc>,
+}
+
+// what became of a delivery
+enum Outcome {
+    Delivered,
+    // worth trying again later, e.g. a timeout or a 503
+    Retry(String),
+    // won't ever go through, e.g. a 404
+    Failed(String),
+}
+
+// the parts of a remote actor document we use
+struct RemoteActor {
+    id: String,
+    inbox: String,
+    shared_inbox: Option<String>,
+    // id -> (owner, PEM public key)
+    keys: BTreeMap<String, (String, String)>,
+}
+
+// the blog as an ActivityPub actor, see https://www.w3.org/TR/activitypub/. Other servers find it
+// through WebFinger and follow it through its inbox, and every new post is delivered to followers as a
+// `Create(Article)` from a queue that retries failures with a backoff. Requests both ways are signed
+// with HTTP Signatures. Shared by every state so followers and the queue survive reloads
+pub(crate) struct ActivityPub {
+    path: PathBuf,
+    client: Arc<dyn HttpClient>,
+    // `None` without `activitypub.private_key`, which leaves federation off
+    key: Option<SigningKey>,
+    stored: Mutex<Stored>,
+    wake: Arc<Notify>,
+}
+
+impl ActivityPub {
+    pub(crate) fn load(config: &Config, client: Arc<dyn HttpClient>) -> Result<Arc<Self>, String> {
+        let activitypub = &config.activitypub;
+        let describe =
+            |err: String| format!("failed to read activitypub state from {}: {}", activitypub.path.display(), err);
+        let stored: Stored = match fs::read(&activitypub.path) {
+            Ok(raw) => serde_json::from_slice(&raw).map_err(|err| describe(err.to_string()))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Stored::default(),
+            Err(err) => return Err(describe(err.to_string())),
+        };
+        let key = match &activitypub.private_key {
+            Some(path) => Some(SigningKey::load(path, format!("{}#main-key", actor_id(config)))?),
+            None => None,
+        };
+        metrics().activitypub_followers.set(stored.followers.len() as i64);
+
+        let wake = Arc::new(Notify::new());
+        let activitypub_state = Arc::new(ActivityPub {
+            path: activitypub.path.clone(),
+            client,
+            key,
+            stored: Mutex::new(stored),
+            wake: wake.clone(),
+        });
+        let retry_base = Duration::from_secs(activitypub.retry_base_secs);
+        tokio::spawn(deliver_queued(
+            Arc::downgrade(&activitypub_state),
+            wake,
+            activitypub.max_attempts,
+            retry_base,
+        ));
+        Ok(activitypub_state)
+    }
+
+    fn is_active(&self, config: &Config) -> bool {
+        config.activitypub.enabled && self.key.is_some()
+    }
+
+    // queues a `Create` for every follower of each post published since the last time
+    pub(crate) fn publish_new(self: &Arc<Self>, config: &Config, content: &Content) {
+        if !self.is_active(config) {
+            return;
+        }
+
+        let mut published = 0;
+        {
+            let mut stored = self.stored.lock().unwrap();
+            let is_first = stored.delivered.is_none();
+            let new_posts: Vec<&Arc<BlogPost>> = {
+                let delivered = stored.delivered.get_or_insert_with(BTreeSet::new);
+                content.posts().iter().filter(|post| delivered.insert(post.id.clone())).collect()
+            };
+            if is_first || new_posts.is_empty() {
+                drop(stored);
+                if is_first {
+                    let activitypub = self.clone();
+                    tokio::spawn(async move { persist(&activitypub).await });
+                }
+                return;
+            }
+
+            let inboxes = follower_inboxes(&stored.followers);
+            // oldest first, so followers see them in the order they were published
+            for post in new_posts.into_iter().rev() {
+                let activity = create(config, post);
+                for inbox in &inboxes {
+                    stored.queue.push(Delivery {
+                        inbox: inbox.clone(),
+                        activity: activity.clone(),
+                        attempts: 0,
+                        next_attempt: Utc::now(),
+                    });
+                }
+                published += 1;
+            }
+        }
+
+        tracing::info!("Queued {} new posts for delivery to followers.", published);
+        self.wake.notify_one();
+        let activitypub = self.clone();
+        tokio::spawn(async move { persist(&activitypub).await });
+    }
+
+    // fetches a remote actor, signing the request as some servers only answer signed fetches
+    async fn fetch_actor(&self, id: &str) -> Result<RemoteActor, String> {
+        // unwrap is fine as `is_active` is checked before anything is fetched
+        let key = self.key.as_ref().unwrap();
+        let mut request = Request::get(id)
+            .header(header::ACCEPT, ACTIVITY_ACCEPT)
+            .body(Bytes::new())
+            .map_err(|err| format!("bad actor id {}: {}", id, err))?;
+        key.sign(&mut request)?;
+        let fetched = self.client.send(request).await?;
+        if !fetched.status.is_success() {
+            return Err(format!("{} answered {}", id, fetched.status));
+        }
+
+        let actor: Value = serde_json::from_slice(&fetched.body).map_err(|err| format!("{} isn't JSON: {}", id, err))?;
+        let keys = match &actor["publicKey"] {
+            Value::Array(keys) => keys.iter().collect(),
+            key => vec![key],
+        };
+        Ok(RemoteActor {
+            id: id_of(&actor["id"]).ok_or_else(|| format!("{} has no id", id))?.to_string(),
+            inbox: actor["inbox"].as_str().ok_or_else(|| format!("{} has no inbox", id))?.to_string(),
+            shared_inbox: actor["endpoints"]["sharedInbox"].as_str().map(str::to_string),
+            keys: keys
+                .into_iter()
+                .filter_map(|key| {
+                    let owner = id_of(&key["owner"])?.to_string();
+                    Some((key["id"].as_str()?.to_string(), (owner, key["publicKeyPem"].as_str()?.to_string())))
+                })
+                .collect(),
+        })
+    }
+
+    // the remote actor who signed an inbox request, once the signature checks out. The error is the
+    // reason for the metrics
+    async fn verify_sender(
+        &self,
+        actor: &str,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<RemoteActor, &'static str> {
+        let signature = Signature::from_headers(headers)?;
+        let remote = self.fetch_actor(actor).await.map_err(|err| {
+            tracing::debug!("Failed to fetch {} to check a signature. Error: {}", actor, err);
+            "unknown_actor"
+        })?;
+        if remote.id != actor {
+            return Err("unknown_actor");
+        }
+        let (_, public_key_pem) = remote
+            .keys
+            .get(&signature.key_id)
+            .filter(|(owner, _)| owner == actor)
+            .ok_or("unknown_key")?;
+        signature.verify(method, uri, headers, body, public_key_pem)?;
+        Ok(remote)
+    }
+
+    fn follow(&self, config: &Config, remote: RemoteActor, follow: Value) {
+        let accept = json!({
+            "@context": ACTIVITY_STREAMS,
+            "id": format!("{}#accepts/{}", actor_id(config), Utc::now().timestamp_micros()),
+            "type": "Accept",
+            "actor": actor_id(config),
+            "object": follow,
+        });
+        let mut stored = self.stored.lock().unwrap();
+        stored.queue.push(Delivery {
+            inbox: remote.inbox.clone(),
+            activity: accept,
+            attempts: 0,
+            next_attempt: Utc::now(),
+        });
+        let follower = Follower {
+            inbox: remote.inbox,
+            shared_inbox: remote.shared_inbox,
+            followed: Utc::now(),
+        };
+        stored.followers.insert(remote.id, follower);
+        metrics().activitypub_followers.set(stored.followers.len() as i64);
+        self.wake.notify_one();
+    }
+
+    fn unfollow(&self, actor: &str) {
+        let mut stored = self.stored.lock().unwrap();
+        stored.followers.remove(actor);
+        metrics().activitypub_followers.set(stored.followers.len() as i64);
+    }
+
+    // tries every delivery that is due, returning when the next of those left is due
+    async fn deliver_due(self: &Arc<Self>, max_attempts: u32, retry_base: Duration) -> Option<DateTime<Utc>> {
+        let now = Utc::now();
+        let due: Vec<Delivery> = {
+            let mut stored = self.stored.lock().unwrap();
+            let (due, waiting) = std::mem::take(&mut stored.queue)
+                .into_iter()
+                .partition(|delivery| delivery.next_attempt <= now);
+            stored.queue = waiting;
+            due
+        };
+
+        if !due.is_empty() {
+            for mut delivery in due {
+                let reason = match self.deliver(&delivery).await {
+                    Outcome::Delivered => {
+                        metrics().activitypub_deliveries.with_label_values(&["delivered"]).inc();
+                        continue;
+                    }
+                    Outcome::Failed(reason) => reason,
+                    Outcome::Retry(reason) if delivery.attempts + 1 < max_attempts => {
+                        delivery.attempts += 1;
+                        let backoff = 2u32.saturating_pow(delivery.attempts - 1);
+                        let wait = retry_base.saturating_mul(backoff).min(MAX_RETRY_WAIT);
+                        // unwrap is fine as the wait is at most a day
+                        delivery.next_attempt = Utc::now() + chrono::Duration::from_std(wait).unwrap();
+                        let inbox = &delivery.inbox;
+                        tracing::debug!("Delivery to {} failed, retrying in {:?}. Error: {}", inbox, wait, reason);
+                        metrics().activitypub_deliveries.with_label_values(&["retried"]).inc();
+                        self.stored.lock().unwrap().queue.push(delivery);
+                        continue;
+                    }
+                    Outcome::Retry(reason) => reason,
+                };
+                tracing::warn!("Gave up delivering to {}. Error: {}", delivery.inbox, reason);
+                metrics().activitypub_deliveries.with_label_values(&["failed"]).inc();
+            }
+            persist(self).await;
+        }
+
+        let stored = self.stored.lock().unwrap();
+        stored.queue.iter().map(|delivery| delivery.next_attempt).min()
+    }
+
+    async fn deliver(&self, delivery: &Delivery) -> Outcome {
+        let Some(key) = &self.key else {
+            return Outcome::Failed("no key to sign with".to_string());
+        };
+        // unwrap is fine as activities are plain JSON values
+        let body = serde_json::to_vec(&delivery.activity).unwrap();
+        let request = Request::post(&delivery.inbox)
+            .header(header::CONTENT_TYPE, ACTIVITY_JSON)
+            .body(Bytes::from(body));
+        let mut request = match request {
+            Ok(request) => request,
+            Err(err) => return Outcome::Failed(format!("bad inbox {}: {}", delivery.inbox, err)),
+        };
+        if let Err(err) = key.sign(&mut request) {
+            return Outcome::Failed(err);
+        }
+
+        match self.client.send(request).await {
+            Ok(fetched) if fetched.status.is_success() => Outcome::Delivered,
+            Ok(fetched) => {
+                let status = fetched.status;
+                let reason = format!("{} answered {}", delivery.inbox, status);
+                let is_temporary = status.is_server_error()
+                    || status == StatusCode::REQUEST_TIMEOUT
+                    || status == StatusCode::TOO_MANY_REQUESTS;
+                if is_temporary {
+                    Outcome::Retry(reason)
+                } else {
+                    Outcome::Failed(reason)
+                }
+            }
+            Err(err) => Outcome::Retry(err),
+        }
+    }
+
+    fn save(&self) {
+        // unwrap is fine as the state is plain strings, dates and JSON
+        let raw = serde_json::to_vec(&*self.stored.lock().unwrap()).unwrap();
+        if let Err(err) = write_atomically(&self.path, &raw) {
+            tracing::error!("Failed to write activitypub state to {}. Error: {:#?}", self.path.display(), err);
+        }
+    }
+}
+
+// delivers whatever is due whenever something is queued or a retry comes up
+async fn deliver_queued(activitypub: Weak<ActivityPub>, wake: Arc<Notify>, max_attempts: u32, retry_base: Duration) {
+    loop {
+        let next_attempt = {
+            let Some(activitypub) = activitypub.upgrade() else {
+                return;
+            };
+            activitypub.deliver_due(max_attempts, retry_base).await
+        };
+        let wait = next_attempt
+            .map(|next_attempt| (next_attempt - Utc::now()).to_std().unwrap_or_default())
+            .unwrap_or(IDLE_WAIT);
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {},
+            _ = wake.notified() => {},
+        }
+    }
+}
+
+// writes the state without holding up the runtime
+async fn persist(activitypub: &Arc<ActivityPub>) {
+    let activitypub = activitypub.clone();
+    if let Err(err) = tokio::task::spawn_blocking(move || activitypub.save()).await {
+        tracing::error!("Failed to write activitypub state. Error: {:#?}", err);
+    }
+}
+
+// one inbox per server where it has a shared inbox, rather than one per follower
+fn follower_inboxes(followers: &BTreeMap<String, Follower>) -> BTreeSet<String> {
+    followers
+        .values()
+        .map(|follower| follower.shared_inbox.clone().unwrap_or_else(|| follower.inbox.clone()))
+        .collect()
+}
+
+// ids are given either as a string or as the object they identify
+fn id_of(value: &Value) -> Option<&str> {
+    value.as_str().or_else(|| value["id"].as_str())
+}
+
+fn actor_id(config: &Config) -> String {
+    format!("{}/actor", config.public_origin())
+}
+
+fn article(config: &Config, post: &BlogPost) -> Value {
+    let url = config.post_url(&post.id);
+    json!({
+        "id": url,
+        "type": "Article",
+        "attributedTo": actor_id(config),
+        "name": post.title,
+        "content": post.content,
+        "url": url,
+        "published": post.published.to_rfc3339(),
+        "updated": post.updated.to_rfc3339(),
+        "to": [PUBLIC],
+        "cc": [format!("{}/followers", config.public_origin())],
+    })
+}
+
+fn create(config: &Config, post: &BlogPost) -> Value {
+    json!({
+        "@context": ACTIVITY_STREAMS,
+        "id": format!("{}#create", config.post_url(&post.id)),
+        "type": "Create",
+        "actor": actor_id(config),
+        "published": post.published.to_rfc3339(),
+        "to": [PUBLIC],
+        "cc": [format!("{}/followers", config.public_origin())],
+        "object": article(config, post),
+    })
+}
+
+fn activity_json(value: Value) -> Response {
+    ([(header::CONTENT_TYPE, HeaderValue::from_static(ACTIVITY_JSON))], Json(value)).into_response()
+}
+
+fn not_found() -> Response {
+    (StatusCode::NOT_FOUND, Json(json!({ "error": "not found" }))).into_response()
+}
+
+// whether a request asks for ActivityStreams rather than html, as servers looking up a post do
+pub(crate) fn wants_activity_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains(ACTIVITY_JSON) || accept.contains("application/ld+json"))
+}
+
+// a post as an `Article`, served from its page for clients that ask for one. Caches are told the page
+// differs by `Accept` so browsers aren't handed the JSON, which has a policy of its own rather than the
+// page's from `cache_control.routes`
+pub(crate) fn article_response(state: &AppState, post: &BlogPost) -> Option<Response> {
+    if !state.activitypub.is_active(&state.config) {
+        return None;
+    }
+    let mut article = article(&state.config, post);
+    article["@context"] = json!(ACTIVITY_STREAMS);
+    let mut response = activity_json(article);
+    let headers = response.headers_mut();
+    headers.insert(header::VARY, HeaderValue::from_static("accept"));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(ARTICLE_CACHE_CONTROL));
+    Some(response)
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WebFingerParams {
+    resource: String,
+}
+
+// how `@<username>@<host>` is turned into the actor, see https://www.rfc-editor.org/rfc/rfc7033
+pub(crate) async fn webfinger(State(state): State<Arc<AppState>>, Query(params): Query<WebFingerParams>) -> Response {
+    let config = &state.config;
+    if !state.activitypub.is_active(config) {
+        return not_found();
+    }
+
+    let origin = config.public_origin();
+    let host = origin.split_once("://").map(|(_, host)| host).unwrap_or(&origin);
+    let subject = format!("acct:{}@{}", config.activitypub.username, host);
+    let actor = actor_id(config);
+    if !params.resource.eq_ignore_ascii_case(&subject) && params.resource != actor {
+        return not_found();
+    }
+    let links = json!({
+        "subject": subject,
+        "aliases": [actor],
+        "links": [
+            { "rel": "self", "type": ACTIVITY_JSON, "href": actor },
+            { "rel": "http://webfinger.net/rel/profile-page", "type": "text/html", "href": origin },
+        ],
+    });
+    ([(header::CONTENT_TYPE, HeaderValue::from_static("application/jrd+json"))], Json(links)).into_response()
+}
+
+// the actor document, with the public key other servers check our signatures against
+pub(crate) async fn actor(State(state): State<Arc<AppState>>) -> Response {
+    let config = &state.config;
+    let Some(key) = state.activitypub.key.as_ref().filter(|_| config.activitypub.enabled) else {
+        return not_found();
+    };
+
+    let origin = config.public_origin();
+    let id = actor_id(config);
+    activity_json(json!({
+        "@context": [ACTIVITY_STREAMS, SECURITY],
+        "id": id,
+        "type": "Person",
+        "preferredUsername": config.activitypub.username,
+        "name": config.activitypub.display_name,
+        "summary": config.activitypub.summary,
+        "url": origin,
+        "inbox": format!("{}/inbox", origin),
+        "outbox": format!("{}/outbox", origin),
+        "followers": format!("{}/followers", origin),
+        "manuallyApprovesFollowers": false,
+        "publicKey": {
+            "id": key.key_id(),
+            "owner": id,
+            "publicKeyPem": key.public_key_pem(),
+        },
+    }))
+}
+
+// every post as a `Create`, newest first
+pub(crate) async fn outbox(State(state): State<Arc<AppState>>) -> Response {
+    let config = &state.config;
+    if !state.activitypub.is_active(config) {
+        return not_found();
+    }
+
+    let items: Vec<Value> = state.content.posts().iter().map(|post| create(config, post)).collect();
+    activity_json(json!({
+        "@context": ACTIVITY_STREAMS,
+        "id": format!("{}/outbox", config.public_origin()),
+        "type": "OrderedCollection",
+        "totalItems": items.len(),
+        "orderedItems": items,
+    }))
+}
+
+// only the number of followers, who they are isn't anyone else's business
+pub(crate) async fn followers(State(state): State<Arc<AppState>>) -> Response {
+    let config = &state.config;
+    if !state.activitypub.is_active(config) {
+        return not_found();
+    }
+
+    let total = state.activitypub.stored.lock().unwrap().followers.len();
+    activity_json(json!({
+        "@context": ACTIVITY_STREAMS,
+        "id": format!("{}/followers", config.public_origin()),
+        "type": "OrderedCollection",
+        "totalItems": total,
+    }))
+}
+
+// takes follows and unfollows. Anything else is accepted and ignored without checking its signature,
+// as servers send plenty we have no use for, e.g. deletes of accounts we have never heard of
+pub(crate) async fn inbox(
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let config = &state.config;
+    let activitypub = &state.activitypub;
+    if !activitypub.is_active(config) {
+        return not_found();
+    }
+
+    let Ok(activity) = serde_json::from_slice::<Value>(&body) else {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": "the body must be an activity" }))).into_response();
+    };
+    let kind = activity["type"].as_str().unwrap_or_default();
+    let actor = id_of(&activity["actor"]).unwrap_or_default();
+    if !matches!(kind, "Follow" | "Undo") || actor.is_empty() {
+        metrics().activitypub_inbox.with_label_values(&["ignored"]).inc();
+        return StatusCode::ACCEPTED.into_response();
+    }
+
+    let remote = match activitypub.verify_sender(actor, &method, &uri, &headers, &body).await {
+        Ok(remote) => remote,
+        Err(reason) => {
+            metrics().activitypub_inbox_rejected.with_label_values(&[reason]).inc();
+            tracing::debug!("Refused a {} from {}, {}.", kind, actor, reason);
+            return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "the request must be signed by its actor" })))
+                .into_response();
+        }
+    };
+
+    let object = &activity["object"];
+    match kind {
+        "Follow" if id_of(object) == Some(actor_id(config).as_str()) => {
+            tracing::info!("{} followed.", actor);
+            activitypub.follow(config, remote, activity.clone());
+            metrics().activitypub_inbox.with_label_values(&["follow"]).inc();
+        }
+        // the follow being undone is embedded by every server that sends these
+        "Undo" if object["type"] == "Follow" && id_of(&object["actor"]).is_none_or(|follower| follower == actor) => {
+            tracing::info!("{} unfollowed.", actor);
+            activitypub.unfollow(actor);
+            metrics().activitypub_inbox.with_label_values(&["undo"]).inc();
+        }
+        _ => {
+            metrics().activitypub_inbox.with_label_values(&["ignored"]).inc();
+            return StatusCode::ACCEPTED.into_response();
+        }
+    }
+    persist(activitypub).await;
+    StatusCode::ACCEPTED.into_response()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::Path;
+
+    use super::*;
+    use crate::{
+        services::get_post,
+        test_support::{config, eventually, testdata, write_post, StubClient, TempDir},
+    };
+
+    const ALICE: &str = "https://remote.example/users/alice";
+    const ALICE_INBOX: &str = "https://remote.example/users/alice/inbox";
+    const INBOX: &str = "https://remote.example/failing/inbox";
+
+    fn state(dir: &TempDir, client: Arc<StubClient>) -> Arc<AppState> {
+        let mut config = config(dir);
+        config.webmention.send = false;
+        config.activitypub.enabled = true;
+        config.activitypub.private_key = Some(testdata("rsa-1.pem"));
+        write_post(&config, "hello", "Hello world");
+        Arc::new(AppState::load_with_client(config, client).unwrap())
+    }
+
+    // alice's server, signing with the second test key
+    fn alice(client: &StubClient) -> SigningKey {
+        let key = SigningKey::load(&testdata("rsa-2.pem"), format!("{}#main-key", ALICE)).unwrap();
+        let actor = json!({
+            "id": ALICE,
+            "type": "Person",
+            "inbox": ALICE_INBOX,
+            "publicKey": { "id": key.key_id(), "owner": ALICE, "publicKeyPem": key.public_key_pem() },
+        });
+        client.respond(ALICE, StatusCode::OK, &[(header::CONTENT_TYPE, ACTIVITY_JSON)], &actor.to_string());
+        client.respond(ALICE_INBOX, StatusCode::ACCEPTED, &[], "");
+        key
+    }
+
+    async fn post_to_inbox(state: &Arc<AppState>, key: Option<&SigningKey>, activity: &Value) -> StatusCode {
+        let mut request = Request::post("https://musings.example/inbox")
+            .header(header::CONTENT_TYPE, ACTIVITY_JSON)
+            .body(Bytes::from(activity.to_string()))
+            .unwrap();
+        if let Some(key) = key {
+            key.sign(&mut request).unwrap();
+        }
+        let (parts, body) = request.into_parts();
+        inbox(State(state.clone()), parts.method, parts.uri, parts.headers, body).await.status()
+    }
+
+    fn follow() -> Value {
+        json!({
+            "id": format!("{}#follows/1", ALICE),
+            "type": "Follow",
+            "actor": ALICE,
+            "object": "https://musings.example/actor",
+        })
+    }
+
+    fn followers(state: &AppState) -> Vec<String> {
+        state.activitypub.stored.lock().unwrap().followers.keys().cloned().collect()
+    }
+
+    #[tokio::test]
+    async fn accepts_signed_follows_and_undos() {
+        let dir = TempDir::new();
+        let client = StubClient::new();
+        let state = state(&dir, client.clone());
+        let key = alice(&client);
+
+        assert_eq!(post_to_inbox(&state, Some(&key), &follow()).await, StatusCode::ACCEPTED);
+        assert_eq!(followers(&state), [ALICE]);
+
+        // the `Accept` is delivered to alice's inbox, signed with our key
+        let mut requests = Vec::new();
+        let delivered = eventually(|| {
+            requests.extend(client.take_requests());
+            requests.iter().any(|request| request.uri() == ALICE_INBOX)
+        })
+        .await;
+        assert!(delivered);
+        let accept = requests.iter().find(|request| request.uri() == ALICE_INBOX).unwrap();
+        let activity: Value = serde_json::from_slice(accept.body()).unwrap();
+        assert_eq!(activity["type"], "Accept");
+        assert_eq!(activity["actor"], "https://musings.example/actor");
+        assert_eq!(activity["object"], follow());
+        let signature = Signature::from_headers(accept.headers()).unwrap();
+        assert_eq!(signature.key_id, "https://musings.example/actor#main-key");
+        let our_key = state.activitypub.key.as_ref().unwrap().public_key_pem();
+        let verified = signature.verify(accept.method(), accept.uri(), accept.headers(), accept.body(), our_key);
+        assert_eq!(verified, Ok(()));
+
+        let undo = json!({ "type": "Undo", "actor": ALICE, "object": follow() });
+        assert_eq!(post_to_inbox(&state, Some(&key), &undo).await, StatusCode::ACCEPTED);
+        assert!(followers(&state).is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_follows_not_signed_by_their_actor() {
+        let dir = TempDir::new();
+        let client = StubClient::new();
+        let state = state(&dir, client.clone());
+        let key = alice(&client);
+
+        assert_eq!(post_to_inbox(&state, None, &follow()).await, StatusCode::UNAUTHORIZED);
+        // signed with a key under alice's key id that isn't hers
+        let impostor = SigningKey::load(&testdata("rsa-1.pem"), format!("{}#main-key", ALICE)).unwrap();
+        assert_eq!(post_to_inbox(&state, Some(&impostor), &follow()).await, StatusCode::UNAUTHORIZED);
+        assert!(followers(&state).is_empty());
+
+        // others can't undo alice's follow either
+        assert_eq!(post_to_inbox(&state, Some(&key), &follow()).await, StatusCode::ACCEPTED);
+        let undo = json!({ "type": "Undo", "actor": ALICE, "object": follow() });
+        assert_eq!(post_to_inbox(&state, Some(&impostor), &undo).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(followers(&state), [ALICE]);
+    }
+
+    #[tokio::test]
+    async fn retries_failed_deliveries_with_a_backoff() {
+        let dir = TempDir::new();
+        let client = StubClient::new();
+        let state = state(&dir, client.clone());
+        let activitypub = &state.activitypub;
+        // lets the delivery queue run once with nothing queued, so it doesn't deliver alongside the test
+        tokio::task::yield_now().await;
+
+        client.respond(INBOX, StatusCode::SERVICE_UNAVAILABLE, &[], "");
+        activitypub.stored.lock().unwrap().queue.push(Delivery {
+            inbox: INBOX.to_string(),
+            activity: json!({ "type": "Create" }),
+            attempts: 0,
+            next_attempt: Utc::now(),
+        });
+        let retry_base = Duration::from_secs(60);
+        let attempts = |expected_wait_secs: i64| {
+            let stored = activitypub.stored.lock().unwrap();
+            let delivery = &stored.queue[0];
+            let wait = (delivery.next_attempt - Utc::now()).num_seconds();
+            assert!((expected_wait_secs - 5..=expected_wait_secs).contains(&wait), "waiting {}s", wait);
+            delivery.attempts
+        };
+        let make_due = || activitypub.stored.lock().unwrap().queue[0].next_attempt = Utc::now();
+
+        // each retry waits twice as long as the one before
+        assert!(activitypub.deliver_due(3, retry_base).await.is_some());
+        assert_eq!(attempts(60), 1);
+        // nothing is tried again before it is due
+        assert!(activitypub.deliver_due(3, retry_base).await.is_some());
+        assert_eq!(attempts(60), 1);
+        make_due();
+        activitypub.deliver_due(3, retry_base).await;
+        assert_eq!(attempts(120), 2);
+        assert_eq!(client.take_requests().len(), 2);
+
+        // and gives up after `max_attempts`
+        make_due();
+        assert_eq!(activitypub.deliver_due(3, retry_base).await, None);
+        assert!(activitypub.stored.lock().unwrap().queue.is_empty());
+
+        // a delivery that can't ever go through isn't retried, and one that goes through is done
+        for status in [StatusCode::NOT_FOUND, StatusCode::ACCEPTED] {
+            client.respond(INBOX, status, &[], "");
+            activitypub.stored.lock().unwrap().queue.push(Delivery {
+                inbox: INBOX.to_string(),
+                activity: json!({ "type": "Create" }),
+                attempts: 0,
+                next_attempt: Utc::now(),
+            });
+            assert_eq!(activitypub.deliver_due(3, retry_base).await, None);
+            assert!(activitypub.stored.lock().unwrap().queue.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_posts_as_articles_to_those_asking() {
+        let dir = TempDir::new();
+        let state = state(&dir, StubClient::new());
+        let headers: HeaderMap = [(header::ACCEPT, HeaderValue::from_static(ACTIVITY_JSON))].into_iter().collect();
+
+        let response = get_post(State(state.clone()), Path("hello".to_string()), headers).await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], ACTIVITY_JSON);
+        assert_eq!(response.headers()[header::VARY], "accept");
+        assert_eq!(response.headers()[header::CACHE_CONTROL], ARTICLE_CACHE_CONTROL);
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::digest::{digest, SHA256};
+
+use crate::config::ServerConfig;
+
+
+
+const SEC_FETCH_SITE: HeaderName = HeaderName::from_static("sec-fetch-site");
+
+// admin endpoints are behind HTTP basic auth with `server.admin_user` and `server.admin_password`, so
+// browsers prompt for them on the admin pages and scrapers send them with every request
+pub(crate) struct AdminCredential {
+    // hash of `user:password`, compared hashed so the time taken doesn't give the password away
+    hash: Vec<u8>,
+}
+
+impl AdminCredential {
+    // `None` without `server.admin_password`, when the admin endpoints are only served on a loopback
+    // `server.admin_port`
+    pub(crate) fn new(server: &ServerConfig) -> Option<Self> {
+        let password = server.admin_password.as_ref()?;
+        Some(AdminCredential {
+            hash: hash(format!("{}:{}", server.admin_user, password).as_bytes()),
+        })
+    }
+
+    fn allows(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+            .is_some_and(|decoded| hash(&decoded) == self.hash)
+    }
+}
+
+pub(crate) async fn require_admin(
+    State(credential): State<Arc<Option<AdminCredential>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(credential) = credential.as_ref() {
+        if !credential.allows(request.headers()) {
+            let challenge = HeaderValue::from_static("Basic realm=\"admin\", charset=\"UTF-8\"");
+            return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, challenge)], "Unauthorized")
+                .into_response();
+        }
+    }
+    // browsers send the credential along with requests from any page, so writes from other sites are
+    // refused. Clients such as curl send no origin and are let through
+    if !matches!(*request.method(), Method::GET | Method::HEAD) && is_cross_site(&request) {
+        tracing::warn!("Refused a cross site admin request.");
+        return (StatusCode::FORBIDDEN, "Forbidden").into_response();
+    }
+    next.run(request).await
+}
+
+// whether a request came from a page on another site, going by `Sec-Fetch-Site` or, from browsers
+// that don't send that, `Origin` against the host the request was sent to
+fn is_cross_site(request: &Request) -> bool {
+    let header = |name| request.headers().get(name).and_then(|value: &HeaderValue| value.to_str().ok());
+    if let Some(site) = header(SEC_FETCH_SITE) {
+        return !matches!(site, "same-origin" | "none");
+    }
+    let Some(origin) = header(header::ORIGIN) else {
+        return false;
+    };
+    // HTTP/2 requests carry the host in the uri rather than a header
+    let host = header(header::HOST).or_else(|| request.uri().authority().map(|authority| authority.as_str()));
+    let origin_host = origin.parse::<Uri>().ok().and_then(|uri| uri.authority().cloned());
+    origin_host.is_none_or(|origin_host| host.is_none_or(|host| origin_host != host))
+}
+
+fn hash(data: &[u8]) -> Vec<u8> {
+    digest(&SHA256, data).as_ref().to_vec()
+}
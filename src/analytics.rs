@@ -0,0 +1,569 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use askama::Template;
+use axum::{
+    extract::{Request, State},
+    http::{header, uri::Authority, HeaderMap, HeaderName, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{Html, Response},
+};
+use chrono::{Datelike, Days, Months, NaiveDate, NaiveTime, Utc};
+use ring::{
+    digest::{Context, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    charts,
+    client_ip::ClientIp,
+    config::{AnalyticsConfig, Config, RankingsConfig},
+    rankings::Rankings,
+    state::AppState,
+};
+
+
+
+const HX_CURRENT_URL: HeaderName = HeaderName::from_static("hx-current-url");
+const HX_REQUEST: HeaderName = HeaderName::from_static("hx-request");
+const DNT: HeaderName = HeaderName::from_static("dnt");
+const SEC_GPC: HeaderName = HeaderName::from_static("sec-gpc");
+
+// referring pages are recorded by path, cut short so odd urls can't bloat the counts
+const MAX_PAGE_LENGTH: usize = 100;
+
+// referring sites and pages come from request headers, so anyone can make up as many as they like.
+// Past this many a day the rest are counted together as `OTHER`
+const MAX_KEYS_PER_DAY: usize = 100;
+const OTHER: &str = "(other)";
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+// periods covered by each chart on the stats page
+const STATS_DAYS: u64 = 30;
+const STATS_WEEKS: u64 = 12;
+const STATS_MONTHS: u32 = 12;
+
+// "most read this week" covers today and the days before it
+const POPULAR_DAYS: u64 = 7;
+// reads older than this no longer count towards trending, whatever the half life
+const TRENDING_DAYS: u64 = 30;
+
+// counts kept in `analytics.path`. Dates are UTC in `DATE_FORMAT`, which sorts chronologically
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct Counts {
+    // date -> link name -> referring page -> clicks
+    clicks: BTreeMap<String, BTreeMap<String, BTreeMap<String, u64>>>,
+    // date -> route -> views
+    views: BTreeMap<String, BTreeMap<String, u64>>,
+    // date -> post id -> reads
+    posts: BTreeMap<String, BTreeMap<String, u64>>,
+    // date -> unique visitors that day
+    visitors: BTreeMap<String, u64>,
+    // date -> referring domain -> page loads
+    referrers: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+// a page worth counting, added to a response by its handler. Posts are counted as read when their
+// fragment is loaded, i.e. scrolled to
+#[derive(Clone)]
+pub(crate) struct PageView {
+    pub(crate) route: &'static str,
+    pub(crate) post: Option<String>,
+}
+
+// visitors are told apart by a hash of their address and user agent with a salt that is replaced
+// every day and never written anywhere, so neither the address nor a lasting identifier is kept.
+// Visitors returning on another day, or after a restart, are counted again
+struct Visitors {
+    date: String,
+    salt: [u8; 32],
+    seen: HashSet<[u8; 16]>,
+}
+
+impl Visitors {
+    fn new(date: String) -> Self {
+        let mut salt = [0; 32];
+        // unwrap is fine as the system random source only fails when the OS has none
+        SystemRandom::new().fill(&mut salt).unwrap();
+        Visitors {
+            date,
+            salt,
+            seen: HashSet::new(),
+        }
+    }
+
+    // whether this is the visitor's first page today
+    fn is_new(&mut self, date: &str, ip: IpAddr, user_agent: &[u8]) -> bool {
+        if self.date != date {
+            *self = Visitors::new(date.to_string());
+        }
+        let mut context = Context::new(&SHA256);
+        context.update(&self.salt);
+        context.update(ip.to_string().as_bytes());
+        context.update(user_agent);
+        let mut visitor = [0; 16];
+        visitor.copy_from_slice(&context.finish().as_ref()[..16]);
+        self.seen.insert(visitor)
+    }
+}
+
+// first party analytics, counted in memory and written to disk every `analytics.flush_interval_secs`
+// and on shutdown. Shared by every state so counts survive reloads
+pub(crate) struct Analytics {
+    path: PathBuf,
+    counts: Mutex<Counts>,
+    visitors: Mutex<Visitors>,
+    // set when there are counts not yet written
+    dirty: AtomicBool,
+    // replaced every `rankings.refresh_interval_secs`, see `refresh_rankings_periodically`
+    rankings: ArcSwap<Rankings>,
+}
+
+impl Analytics {
+    pub(crate) fn load(config: &AnalyticsConfig, rankings: &RankingsConfig) -> Result<Arc<Self>, String> {
+        let counts = match fs::read(&config.path) {
+            Ok(raw) => serde_json::from_slice(&raw)
+                .map_err(|err| format!("failed to read analytics from {}: {}", config.path.display(), err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Counts::default(),
+            Err(err) => return Err(format!("failed to read analytics from {}: {}", config.path.display(), err)),
+        };
+
+        let analytics = Arc::new(Analytics {
+            path: config.path.clone(),
+            counts: Mutex::new(counts),
+            visitors: Mutex::new(Visitors::new(today())),
+            dirty: AtomicBool::new(false),
+            rankings: ArcSwap::from_pointee(Rankings::default()),
+        });
+        analytics.refresh_rankings(rankings.half_life_hours);
+
+        // both stop once the analytics are dropped, the final counts are written by `flush` on shutdown
+        let weak = Arc::downgrade(&analytics);
+        tokio::spawn(flush_periodically(weak.clone(), Duration::from_secs(config.flush_interval_secs)));
+        tokio::spawn(refresh_rankings_periodically(
+            weak,
+            Duration::from_secs(rankings.refresh_interval_secs),
+            rankings.half_life_hours,
+        ));
+
+        Ok(analytics)
+    }
+
+    // counts a click on the named link from a client that isn't a bot
+    pub(crate) fn record_click(&self, config: &Config, link: &str, headers: &HeaderMap) {
+        if is_bot(&config.analytics, headers) {
+            return;
+        }
+        let today = today();
+        let page = referring_page(&config.public_origin(), headers);
+
+        let mut counts = self.counts.lock().unwrap();
+        let pages = counts.clicks.entry(today).or_default().entry(link.to_string()).or_default();
+        count_capped(pages, page);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // counts a page view, unless from a bot or a client asking not to be tracked through `DNT` or
+    // `Sec-GPC`. Referring sites are only counted for full page loads, htmx requests come from the page
+    // itself
+    fn record_page_view(&self, config: &Config, view: &PageView, client_ip: Option<IpAddr>, headers: &HeaderMap) {
+        if is_bot(&config.analytics, headers) || is_opted_out(headers) {
+            return;
+        }
+        let today = today();
+        let is_new_visitor = client_ip.is_some_and(|ip| {
+            let user_agent = headers.get(header::USER_AGENT).map(|value| value.as_bytes()).unwrap_or_default();
+            self.visitors.lock().unwrap().is_new(&today, ip, user_agent)
+        });
+        let referrer = if headers.contains_key(HX_REQUEST) {
+            None
+        } else {
+            referring_domain(&config.public_origin(), headers)
+        };
+
+        let mut counts = self.counts.lock().unwrap();
+        *counts.views.entry(today.clone()).or_default().entry(view.route.to_string()).or_default() += 1;
+        if let Some(post) = &view.post {
+            *counts.posts.entry(today.clone()).or_default().entry(post.clone()).or_default() += 1;
+        }
+        if is_new_visitor {
+            *counts.visitors.entry(today.clone()).or_default() += 1;
+        }
+        if let Some(referrer) = referrer {
+            count_capped(counts.referrers.entry(today).or_default(), referrer);
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // writes the counts if anything changed since the last write. The file is replaced in one go so a
+    // crash part way through leaves the previous counts
+    pub(crate) fn flush(&self) {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        // unwrap is fine as the counts are plain maps of strings and numbers
+        let raw = serde_json::to_vec(&*self.counts.lock().unwrap()).unwrap();
+        if let Err(err) = write_atomically(&self.path, &raw) {
+            self.dirty.store(true, Ordering::Relaxed);
+            tracing::error!("Failed to write analytics to {}. Error: {:#?}", self.path.display(), err);
+        }
+    }
+
+    // the rankings as of the last refresh
+    pub(crate) fn rankings(&self) -> Arc<Rankings> {
+        self.rankings.load_full()
+    }
+
+    // ranks posts by reads over the last `POPULAR_DAYS` days, and by a trending score where each read
+    // counts half as much for every `half_life_hours` since. Reads are only counted by day, so each
+    // day's are taken to be from midday, or from now for today's before midday
+    fn refresh_rankings(&self, half_life_hours: u64) {
+        let now = Utc::now();
+        let today = now.date_naive();
+        let popular_since = (today - Days::new(POPULAR_DAYS - 1)).format(DATE_FORMAT).to_string();
+        let trending_since = (today - Days::new(TRENDING_DAYS - 1)).format(DATE_FORMAT).to_string();
+
+        let mut popular: BTreeMap<&str, u64> = BTreeMap::new();
+        let mut trending: BTreeMap<&str, f64> = BTreeMap::new();
+        let counts = self.counts.lock().unwrap();
+        for (date, posts) in counts.posts.range(trending_since..) {
+            let Ok(day) = NaiveDate::parse_from_str(date, DATE_FORMAT) else {
+                continue;
+            };
+            // unwrap is fine as midday is a valid time
+            let read_at = day.and_time(NaiveTime::from_hms_opt(12, 0, 0).unwrap()).and_utc().min(now);
+            let age_hours = (now - read_at).num_seconds() as f64 / 3600.0;
+            let weight = 0.5_f64.powf(age_hours / half_life_hours as f64);
+            for (post, reads) in posts {
+                if *date >= popular_since {
+                    *popular.entry(post).or_default() += reads;
+                }
+                *trending.entry(post).or_default() += *reads as f64 * weight;
+            }
+        }
+
+        let mut trending: Vec<(String, f64)> =
+            trending.into_iter().map(|(post, score)| (post.to_string(), score)).collect();
+        trending.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let rankings = Rankings {
+            popular: most_first(popular),
+            trending,
+            computed_at: Some(now),
+        };
+        drop(counts);
+        self.rankings.store(Arc::new(rankings));
+    }
+
+    // clicks over the last `days` days, for the stats page
+    fn click_stats(&self, days: u64) -> ClickStats {
+        let today = Utc::now().date_naive();
+        let since = (today - Days::new(days - 1)).format(DATE_FORMAT).to_string();
+        let counts = self.counts.lock().unwrap();
+
+        let mut by_day = BTreeMap::new();
+        for offset in (0..days).rev() {
+            by_day.insert((today - Days::new(offset)).format(DATE_FORMAT).to_string(), 0);
+        }
+        let mut by_link: BTreeMap<&str, u64> = BTreeMap::new();
+        let mut by_page: BTreeMap<&str, u64> = BTreeMap::new();
+        for (date, links) in counts.clicks.range(since..) {
+            for (link, pages) in links {
+                for (page, clicks) in pages {
+                    *by_day.entry(date.clone()).or_default() += clicks;
+                    *by_link.entry(link).or_default() += clicks;
+                    *by_page.entry(page).or_default() += clicks;
+                }
+            }
+        }
+
+        ClickStats {
+            by_day: by_day.into_iter().collect(),
+            by_link: most_first(by_link),
+            by_page: most_first(by_page),
+        }
+    }
+
+    // page views and visitors by day, ISO week and month, with the most read posts and referring
+    // sites over the last `STATS_DAYS` days
+    fn page_stats(&self) -> PageStats {
+        let today = Utc::now().date_naive();
+        let counts = self.counts.lock().unwrap();
+
+        let views_between = |first: NaiveDate, last: NaiveDate| {
+            let (first, last) = (first.format(DATE_FORMAT).to_string(), last.format(DATE_FORMAT).to_string());
+            let views = counts.views.range(first.clone()..=last.clone()).flat_map(|(_, routes)| routes.values()).sum();
+            let visitors = counts.visitors.range(first..=last).map(|(_, visitors)| visitors).sum();
+            (views, visitors)
+        };
+        let period = |label: String, (views, visitors): (u64, u64)| charts::Period { label, views, visitors };
+
+        let daily: Vec<_> = (0..STATS_DAYS)
+            .rev()
+            .map(|offset| today - Days::new(offset))
+            .map(|day| period(day.format("%-d %b").to_string(), views_between(day, day)))
+            .collect();
+        let this_week = today - Days::new(u64::from(today.weekday().num_days_from_monday()));
+        let weekly: Vec<_> = (0..STATS_WEEKS)
+            .rev()
+            .map(|offset| this_week - Days::new(offset * 7))
+            .map(|monday| {
+                let week = monday.iso_week();
+                period(format!("W{}", week.week()), views_between(monday, monday + Days::new(6)))
+            })
+            .collect();
+        // unwrap is fine as the first of the month always exists
+        let this_month = today.with_day(1).unwrap();
+        let monthly: Vec<_> = (0..STATS_MONTHS)
+            .rev()
+            .map(|offset| this_month - Months::new(offset))
+            .map(|first| {
+                let last = first + Months::new(1) - Days::new(1);
+                period(first.format("%b %Y").to_string(), views_between(first, last))
+            })
+            .collect();
+
+        let since = (today - Days::new(STATS_DAYS - 1)).format(DATE_FORMAT).to_string();
+        let totals = |counts: &BTreeMap<String, BTreeMap<String, u64>>| {
+            let mut totals: BTreeMap<&str, u64> = BTreeMap::new();
+            for (name, count) in counts.range(since.clone()..).flat_map(|(_, names)| names) {
+                *totals.entry(name).or_default() += count;
+            }
+            most_first(totals)
+        };
+
+        PageStats {
+            daily: charts::views_chart(&daily),
+            weekly: charts::views_chart(&weekly),
+            monthly: charts::views_chart(&monthly),
+            posts: totals(&counts.posts),
+            referrers: totals(&counts.referrers),
+        }
+    }
+}
+
+// counts the page views handlers mark with a `PageView`, see `Analytics::record_page_view`
+pub(crate) async fn track_page_views(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let is_get = request.method() == Method::GET;
+    let headers = request.headers().clone();
+    let client_ip = request.extensions().get::<ClientIp>().and_then(|ClientIp(ip)| *ip);
+
+    let response = next.run(request).await;
+
+    if let Some(view) = response.extensions().get::<PageView>() {
+        // a revalidated page is a repeat read too, `http_cache` answers those with a 304
+        if is_get && matches!(response.status(), StatusCode::OK | StatusCode::NOT_MODIFIED) {
+            state
+                .analytics
+                .record_page_view(&state.config, view, client_ip, &headers);
+        }
+    }
+    response
+}
+
+fn today() -> String {
+    Utc::now().format(DATE_FORMAT).to_string()
+}
+
+async fn flush_periodically(analytics: Weak<Analytics>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let Some(analytics) = analytics.upgrade() else {
+            return;
+        };
+        tokio::task::spawn_blocking(move || analytics.flush());
+    }
+}
+
+async fn refresh_rankings_periodically(analytics: Weak<Analytics>, interval: Duration, half_life_hours: u64) {
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let Some(analytics) = analytics.upgrade() else {
+            return;
+        };
+        tokio::task::spawn_blocking(move || analytics.refresh_rankings(half_life_hours));
+    }
+}
+
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+}
+
+fn is_opted_out(headers: &HeaderMap) -> bool {
+    [DNT, SEC_GPC].iter().any(|name| headers.get(name).is_some_and(|value| value == "1"))
+}
+
+// crawlers, link previews and scripts, anything without a user agent counts as one too
+fn is_bot(config: &AnalyticsConfig, headers: &HeaderMap) -> bool {
+    let Some(user_agent) = headers.get(header::USER_AGENT).and_then(|value| value.to_str().ok()) else {
+        return true;
+    };
+    let user_agent = user_agent.to_ascii_lowercase();
+    config
+        .bot_user_agents
+        .iter()
+        .any(|bot| user_agent.contains(&bot.to_ascii_lowercase()))
+}
+
+// path of the page on this site the request came from. htmx names it in `HX-Current-URL`, plain links
+// through `Referer`. Pages anywhere but `server.public_url` are all counted as external
+fn referring_page(public_origin: &str, headers: &HeaderMap) -> String {
+    let Some(referrer) = headers
+        .get(HX_CURRENT_URL)
+        .or_else(|| headers.get(header::REFERER))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<Uri>().ok())
+    else {
+        return "(none)".to_string();
+    };
+
+    if referrer.authority() != authority(public_origin).as_ref() {
+        return "(external)".to_string();
+    }
+    // paths in a parsed uri are plain ascii, so can be cut anywhere
+    let mut page = referrer.path().to_string();
+    page.truncate(MAX_PAGE_LENGTH);
+    page
+}
+
+// host of the site that linked to this one, `None` for direct visits and links within the site
+fn referring_domain(public_origin: &str, headers: &HeaderMap) -> Option<String> {
+    let referrer = headers
+        .get(header::REFERER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<Uri>().ok())?;
+    let referrer_host = referrer.host()?.to_ascii_lowercase();
+
+    if authority(public_origin).is_some_and(|public| public.host().eq_ignore_ascii_case(&referrer_host)) {
+        return None;
+    }
+    Some(referrer_host.strip_prefix("www.").unwrap_or(&referrer_host).to_string())
+}
+
+fn authority(url: &str) -> Option<Authority> {
+    url.parse::<Uri>().ok()?.authority().cloned()
+}
+
+// adds one to the count for `key`, or to `OTHER` once there are already `MAX_KEYS_PER_DAY` others
+fn count_capped(counts: &mut BTreeMap<String, u64>, key: String) {
+    let key = if counts.len() >= MAX_KEYS_PER_DAY && !counts.contains_key(&key) {
+        OTHER.to_string()
+    } else {
+        key
+    };
+    *counts.entry(key).or_default() += 1;
+}
+
+fn most_first(counts: BTreeMap<&str, u64>) -> Vec<(String, u64)> {
+    let mut counts: Vec<(String, u64)> = counts.into_iter().map(|(name, count)| (name.to_string(), count)).collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+// click totals for the stats page
+struct ClickStats {
+    // every day in the window, oldest first
+    by_day: Vec<(String, u64)>,
+    // most clicked first
+    by_link: Vec<(String, u64)>,
+    by_page: Vec<(String, u64)>,
+}
+
+// page view charts as svg and totals for the stats page
+struct PageStats {
+    daily: String,
+    weekly: String,
+    monthly: String,
+    // most read first
+    posts: Vec<(String, u64)>,
+    referrers: Vec<(String, u64)>,
+}
+
+
+#[derive(Template)]
+#[template(path = "stats.html")]
+struct StatsTemplate {
+    days: u64,
+    weeks: u64,
+    months: u32,
+    pages: PageStats,
+    clicks: ClickStats,
+}
+
+// admin page summarising the analytics
+pub(crate) async fn serve_stats(State(state): State<Arc<AppState>>) -> Html<String> {
+    let stats = StatsTemplate {
+        days: STATS_DAYS,
+        weeks: STATS_WEEKS,
+        months: STATS_MONTHS,
+        pages: state.analytics.page_stats(),
+        clicks: state.analytics.click_stats(STATS_DAYS),
+    };
+    Html(stats.render().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: &str = "https://musings.example.com";
+
+    fn headers(pairs: &[(HeaderName, &str)]) -> HeaderMap {
+        pairs.iter().map(|(name, value)| (name.clone(), value.parse().unwrap())).collect()
+    }
+
+    #[test]
+    fn counts_past_the_cap_as_other() {
+        let mut counts = BTreeMap::new();
+        for i in 0..MAX_KEYS_PER_DAY + 10 {
+            count_capped(&mut counts, format!("site-{}.example", i));
+        }
+        count_capped(&mut counts, "site-0.example".to_string());
+
+        assert_eq!(counts.len(), MAX_KEYS_PER_DAY + 1);
+        assert_eq!(counts[OTHER], 10);
+        assert_eq!(counts["site-0.example"], 2);
+    }
+
+    #[test]
+    fn only_takes_referring_pages_from_the_public_origin() {
+        let from_site = headers(&[(header::REFERER, "https://musings.example.com/posts/hello")]);
+        assert_eq!(referring_page(ORIGIN, &from_site), "/posts/hello");
+
+        // a made up host doesn't make a page elsewhere count as one of ours
+        let forged = headers(&[
+            (header::HOST, "evil.example"),
+            (header::REFERER, "https://evil.example/some/made/up/page"),
+        ]);
+        assert_eq!(referring_page(ORIGIN, &forged), "(external)");
+        assert_eq!(referring_page(ORIGIN, &HeaderMap::new()), "(none)");
+    }
+
+    #[test]
+    fn leaves_out_links_within_the_site_from_referring_domains() {
+        let within = headers(&[(header::REFERER, "https://musings.example.com/")]);
+        assert_eq!(referring_domain(ORIGIN, &within), None);
+
+        let elsewhere = headers(&[(header::REFERER, "https://www.news.example/item?id=1")]);
+        assert_eq!(referring_domain(ORIGIN, &elsewhere).as_deref(), Some("news.example"));
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt::Write;
+
+
+
+const WIDTH: f64 = 640.0;
+const HEIGHT: f64 = 220.0;
+// room for the axis labels
+const LEFT: f64 = 40.0;
+const BOTTOM: f64 = 24.0;
+const TOP: f64 = 12.0;
+
+const VIEWS_COLOUR: &str = "#93c5fd";
+const VISITORS_COLOUR: &str = "#1d4ed8";
+
+// one bar of a chart
+pub(crate) struct Period {
+    pub(crate) label: String,
+    pub(crate) views: u64,
+    pub(crate) visitors: u64,
+}
+
+// bar chart of views with visitors drawn over it as a line, rendered server side so the stats page
+// needs no javascript. Only some periods are labelled to keep the labels from overlapping
+pub(crate) fn views_chart(periods: &[Period]) -> String {
+    let plot_width = WIDTH - LEFT;
+    let plot_height = HEIGHT - TOP - BOTTOM;
+    let max = periods.iter().map(|period| period.views.max(period.visitors)).max().unwrap_or(0).max(1);
+    let slot = plot_width / periods.len().max(1) as f64;
+    let y = |value: u64| TOP + plot_height - value as f64 / max as f64 * plot_height;
+    let label_every = periods.len().div_ceil(8).max(1);
+
+    // writing to a String can't fail
+    let mut svg = String::new();
+    let _ = write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {WIDTH} {HEIGHT}" width="{WIDTH}" height="{HEIGHT}" role="img" font-family="sans-serif" font-size="10">"#
+    );
+    let _ = write!(
+        svg,
+        r##"<line x1="{LEFT}" y1="{}" x2="{WIDTH}" y2="{}" stroke="#9ca3af"/><text x="{}" y="{}" text-anchor="end">{max}</text><text x="{}" y="{}" text-anchor="end">0</text>"##,
+        y(0),
+        y(0),
+        LEFT - 4.0,
+        y(max) + 4.0,
+        LEFT - 4.0,
+        y(0),
+    );
+
+    let mut line = Vec::with_capacity(periods.len());
+    for (index, period) in periods.iter().enumerate() {
+        let x = LEFT + index as f64 * slot;
+        let _ = write!(
+            svg,
+            r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="{VIEWS_COLOUR}"><title>{}: {} views, {} visitors</title></rect>"#,
+            x + slot * 0.1,
+            y(period.views),
+            slot * 0.8,
+            y(0) - y(period.views),
+            escape(&period.label),
+            period.views,
+            period.visitors,
+        );
+        line.push(format!("{:.1},{:.1}", x + slot / 2.0, y(period.visitors)));
+        if index % label_every == 0 {
+            let _ = write!(
+                svg,
+                r#"<text x="{:.1}" y="{}" text-anchor="middle">{}</text>"#,
+                x + slot / 2.0,
+                HEIGHT - 8.0,
+                escape(&period.label),
+            );
+        }
+    }
+    let _ = write!(
+        svg,
+        r#"<polyline points="{}" fill="none" stroke="{VISITORS_COLOUR}" stroke-width="2"/></svg>"#,
+        line.join(" ")
+    );
+    svg
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
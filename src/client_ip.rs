@@ -0,0 +1,109 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderName},
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+
+
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+// address of the client that made the request, resolved once by `resolve_client_ip` and used for
+// rate limiting, the access log and the `http_request` span. `None` when there is no peer address
+// and no forwarding headers to go on, e.g. a proxy on the unix socket not setting X-Forwarded-For
+#[derive(Clone, Copy)]
+pub(crate) struct ClientIp(pub(crate) Option<IpAddr>);
+
+pub(crate) async fn resolve_client_ip(
+    State(trusted_proxies): State<Arc<Vec<IpNet>>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let client_ip = client_ip(&request, &trusted_proxies);
+    request.extensions_mut().insert(ClientIp(client_ip));
+    next.run(request).await
+}
+
+// forwarding headers are only believed when the peer is a trusted proxy, otherwise any client could
+// pick its own address. Peers on the unix socket have no address and can only be local processes,
+// so are trusted
+fn client_ip<T>(request: &Request<T>, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|network| network.contains(ip));
+
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical());
+    if peer.is_some_and(|peer| !is_trusted(&peer)) {
+        return peer;
+    }
+
+    // each proxy appends the address it received the request from, so the rightmost address not
+    // belonging to a trusted proxy is the client. Anything further left was sent by the client and
+    // can't be relied on
+    let chain = forwarded_chain(request.headers());
+    let forwarded = chain
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(ip))
+        .or(chain.first())
+        .copied();
+
+    forwarded
+        .or_else(|| {
+            request
+                .headers()
+                .get(X_REAL_IP)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_node)
+        })
+        .or(peer)
+}
+
+// addresses from `X-Forwarded-For`, or the `for` parameters of `Forwarded` when that's absent, in
+// order from the original client to the last proxy
+fn forwarded_chain(headers: &HeaderMap) -> Vec<IpAddr> {
+    let x_forwarded_for: Vec<IpAddr> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(parse_node)
+        .collect();
+    if !x_forwarded_for.is_empty() {
+        return x_forwarded_for;
+    }
+
+    headers
+        .get_all(header::FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                name.trim().eq_ignore_ascii_case("for").then_some(value)
+            })
+        })
+        .filter_map(parse_node)
+        .collect()
+}
+
+// accepts a bare address or one with a port, with IPv6 optionally in brackets and the whole thing
+// optionally quoted as in `Forwarded: for="[2001:db8::1]:4711"`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| node.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+        .map(|ip| ip.to_canonical())
+}
@@ -0,0 +1,668 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use askama::Template;
+use axum::{
+    extract::{Path, Query, State},
+    response::{Html, IntoResponse, Response},
+    Form,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, TimeDelta, Utc};
+use pulldown_cmark::{html, Event, Options, Parser, Tag, TagEnd};
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    analytics::write_atomically,
+    config::CommentsConfig,
+    metrics::metrics,
+    services::handler_404,
+    spam::{count_links, Classifier, FormStamps},
+    state::AppState,
+};
+
+
+
+// caught spam listed on the moderation page
+const CAUGHT_SPAM_SHOWN: usize = 20;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Status {
+    Pending,
+    Approved,
+    Rejected,
+    Spam,
+}
+
+impl Status {
+    // the moderator actions on the admin `/moderation` page
+    fn from_action(action: &str) -> Option<Self> {
+        match action {
+            "approve" => Some(Status::Approved),
+            "reject" => Some(Status::Rejected),
+            "spam" => Some(Status::Spam),
+            _ => None,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Status::Pending => "pending",
+            Status::Approved => "approved",
+            Status::Rejected => "rejected",
+            Status::Spam => "spam",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Comment {
+    pub(crate) id: u64,
+    pub(crate) post: String,
+    // the comment this replies to, `None` for comments on the post itself
+    pub(crate) parent: Option<u64>,
+    pub(crate) author: String,
+    // markdown as written, see `render_markdown`
+    pub(crate) body: String,
+    pub(crate) created: DateTime<Utc>,
+    pub(crate) edited: Option<DateTime<Utc>>,
+    pub(crate) status: Status,
+    // how likely the classifier thought the comment was spam when it came in, if it was trained enough
+    #[serde(default)]
+    pub(crate) spam_score: Option<f64>,
+    // set once a moderator has decided on the comment, and the classifier learnt from it
+    #[serde(default)]
+    pub(crate) moderated: bool,
+    // hash of the token the commenter was given to edit the comment, the token itself isn't kept
+    token_hash: String,
+}
+
+// everything kept in `comments.path`. Rejected and spam comments are kept too, they are never shown
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct Stored {
+    // ids count up from 1
+    last_id: u64,
+    comments: BTreeMap<u64, Comment>,
+    classifier: Classifier,
+}
+
+impl Stored {
+    // files the comment as spam if the classifier is sure enough it is
+    fn classify(&self, config: &CommentsConfig, comment: &mut Comment) {
+        comment.spam_score =
+            self.classifier
+                .spam_probability(&comment.author, &comment.body, config.classifier_min_samples);
+        if comment.spam_score.is_some_and(|score| score >= config.spam_threshold) {
+            comment.status = Status::Spam;
+            metrics().comments_blocked.with_label_values(&["classifier"]).inc();
+        }
+    }
+
+    // how many replies deep a comment is, 0 for comments on the post itself
+    fn depth(&self, id: u64) -> usize {
+        let mut depth = 0;
+        let mut parent = self.comments.get(&id).and_then(|comment| comment.parent);
+        while let Some(id) = parent {
+            depth += 1;
+            parent = self.comments.get(&id).and_then(|comment| comment.parent);
+        }
+        depth
+    }
+}
+
+// reader comments on every post, written to disk on every change. Shared by every state so
+// comments survive reloads
+pub(crate) struct Comments {
+    path: PathBuf,
+    stored: Mutex<Stored>,
+    stamps: FormStamps,
+}
+
+impl Comments {
+    pub(crate) fn load(config: &CommentsConfig) -> Result<Arc<Self>, String> {
+        let stored = match fs::read(&config.path) {
+            Ok(raw) => serde_json::from_slice(&raw)
+                .map_err(|err| format!("failed to read comments from {}: {}", config.path.display(), err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Stored::default(),
+            Err(err) => return Err(format!("failed to read comments from {}: {}", config.path.display(), err)),
+        };
+        Ok(Arc::new(Comments {
+            path: config.path.clone(),
+            stored: Mutex::new(stored),
+            stamps: FormStamps::new(),
+        }))
+    }
+
+    // adds a comment on `post`, returning it with the token that allows editing it. Errors are meant
+    // for the commenter
+    fn submit(
+        &self,
+        config: &CommentsConfig,
+        post: &str,
+        parent: Option<u64>,
+        author: &str,
+        body: &str,
+    ) -> Result<(Comment, String), String> {
+        let author = check_author(config, author)?;
+        let body = check_body(config, body)?;
+
+        let mut stored = self.stored.lock().unwrap();
+        if let Some(parent) = parent {
+            let is_visible = stored
+                .comments
+                .get(&parent)
+                .is_some_and(|parent| parent.post == post && parent.status == Status::Approved);
+            if !is_visible {
+                return Err("The comment you replied to is no longer there.".to_string());
+            }
+            if stored.depth(parent) >= config.max_depth {
+                return Err("Replies can't be nested any deeper.".to_string());
+            }
+        }
+
+        let token = new_token();
+        stored.last_id += 1;
+        let mut comment = Comment {
+            id: stored.last_id,
+            post: post.to_string(),
+            parent,
+            author,
+            body,
+            created: Utc::now(),
+            edited: None,
+            status: if config.moderate { Status::Pending } else { Status::Approved },
+            spam_score: None,
+            moderated: false,
+            token_hash: hash_token(&token),
+        };
+        stored.classify(config, &mut comment);
+        stored.comments.insert(comment.id, comment.clone());
+        Ok((comment, token))
+    }
+
+    // replaces the body of a comment still within `comments.edit_window_secs`, given the token handed
+    // out with it. Under moderation an edited comment goes back in the queue
+    fn edit(&self, config: &CommentsConfig, id: u64, token: &str, body: &str) -> Result<Comment, String> {
+        let body = check_body(config, body)?;
+
+        let mut stored = self.stored.lock().unwrap();
+        let Stored { comments, classifier, .. } = &mut *stored;
+        // unknown ids and wrong tokens are told apart from nothing, so ids can't be probed
+        let comment = comments
+            .get_mut(&id)
+            .filter(|comment| comment.token_hash == hash_token(token))
+            .filter(|comment| matches!(comment.status, Status::Pending | Status::Approved))
+            .ok_or_else(|| "This comment can't be edited.".to_string())?;
+        if Utc::now() > edit_deadline(config, comment) {
+            return Err("The time for editing this comment has passed.".to_string());
+        }
+
+        // what was approved is no longer what the comment says
+        if comment.moderated {
+            classifier.forget(&comment.author, &comment.body, false);
+            comment.moderated = false;
+        }
+        comment.body = body;
+        comment.edited = Some(Utc::now());
+        if config.moderate {
+            comment.status = Status::Pending;
+        }
+        let mut comment = comment.clone();
+        stored.classify(config, &mut comment);
+        stored.comments.insert(comment.id, comment.clone());
+        Ok(comment)
+    }
+
+    // approved comments on a post, each followed by its replies, along with how deep each one is.
+    // Replies to comments that aren't shown aren't shown either
+    fn thread(&self, post: &str) -> Vec<(Comment, usize)> {
+        let stored = self.stored.lock().unwrap();
+        let mut replies: BTreeMap<Option<u64>, Vec<&Comment>> = BTreeMap::new();
+        for comment in stored.comments.values() {
+            if comment.post == post && comment.status == Status::Approved {
+                replies.entry(comment.parent).or_default().push(comment);
+            }
+        }
+
+        let mut thread = Vec::new();
+        let mut stack: Vec<(&Comment, usize)> =
+            replies.get(&None).into_iter().flatten().rev().map(|comment| (*comment, 0)).collect();
+        while let Some((comment, depth)) = stack.pop() {
+            thread.push((comment.clone(), depth));
+            let children = replies.get(&Some(comment.id)).into_iter().flatten().rev();
+            stack.extend(children.map(|child| (*child, depth + 1)));
+        }
+        thread
+    }
+
+    // the comment, if the token is the one it was submitted with
+    fn with_token(&self, id: u64, token: &str) -> Option<Comment> {
+        let stored = self.stored.lock().unwrap();
+        stored.comments.get(&id).filter(|comment| comment.token_hash == hash_token(token)).cloned()
+    }
+
+    // comments awaiting moderation, oldest first
+    fn queue(&self) -> Vec<Comment> {
+        let stored = self.stored.lock().unwrap();
+        stored
+            .comments
+            .values()
+            .filter(|comment| comment.status == Status::Pending)
+            .cloned()
+            .collect()
+    }
+
+    // spam the classifier caught that no moderator has looked at yet, newest first
+    fn caught(&self, limit: usize) -> Vec<Comment> {
+        let stored = self.stored.lock().unwrap();
+        stored
+            .comments
+            .values()
+            .rev()
+            .filter(|comment| comment.status == Status::Spam && !comment.moderated)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    // applies a moderator's decision, teaching the classifier approved comments as ham and spam as
+    // spam. A decision made earlier on the same comment is unlearnt first
+    fn moderate(&self, id: u64, status: Status) -> Option<Comment> {
+        let mut stored = self.stored.lock().unwrap();
+        let Stored { comments, classifier, .. } = &mut *stored;
+        let comment = comments.get_mut(&id)?;
+        if comment.moderated {
+            match comment.status {
+                Status::Approved => classifier.forget(&comment.author, &comment.body, false),
+                Status::Spam => classifier.forget(&comment.author, &comment.body, true),
+                Status::Pending | Status::Rejected => {}
+            }
+        }
+        match status {
+            Status::Approved => classifier.learn(&comment.author, &comment.body, false),
+            Status::Spam => classifier.learn(&comment.author, &comment.body, true),
+            Status::Pending | Status::Rejected => {}
+        }
+        comment.status = status;
+        comment.moderated = true;
+        Some(comment.clone())
+    }
+
+    fn save(&self) {
+        // unwrap is fine as comments are plain strings, numbers and dates
+        let raw = serde_json::to_vec(&*self.stored.lock().unwrap()).unwrap();
+        if let Err(err) = write_atomically(&self.path, &raw) {
+            tracing::error!("Failed to write comments to {}. Error: {:#?}", self.path.display(), err);
+        }
+    }
+}
+
+// writes the comments without holding up the runtime
+async fn persist(comments: &Arc<Comments>) {
+    let comments = comments.clone();
+    if let Err(err) = tokio::task::spawn_blocking(move || comments.save()).await {
+        tracing::error!("Failed to write comments. Error: {:#?}", err);
+    }
+}
+
+fn check_author(config: &CommentsConfig, author: &str) -> Result<String, String> {
+    let author = author.trim();
+    if author.chars().count() > config.max_author_length {
+        return Err(format!("Names can be at most {} characters.", config.max_author_length));
+    }
+    Ok(if author.is_empty() { "Anonymous" } else { author }.to_string())
+}
+
+fn check_body(config: &CommentsConfig, body: &str) -> Result<String, String> {
+    let body = body.trim();
+    if body.is_empty() {
+        return Err("Your comment is empty.".to_string());
+    }
+    if body.chars().count() > config.max_length {
+        return Err(format!("Comments can be at most {} characters.", config.max_length));
+    }
+    if count_links(body) > config.max_links {
+        metrics().comments_blocked.with_label_values(&["links"]).inc();
+        return Err(format!("Comments can have at most {} links.", config.max_links));
+    }
+    Ok(body.to_string())
+}
+
+fn edit_deadline(config: &CommentsConfig, comment: &Comment) -> DateTime<Utc> {
+    // saturates rather than overflowing on absurdly long windows
+    let window = i64::try_from(config.edit_window_secs)
+        .ok()
+        .and_then(TimeDelta::try_seconds)
+        .unwrap_or(TimeDelta::MAX);
+    comment.created.checked_add_signed(window).unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+fn new_token() -> String {
+    let mut token = [0; 32];
+    // unwrap is fine as the system random source only fails when the OS has none
+    SystemRandom::new().fill(&mut token).unwrap();
+    URL_SAFE_NO_PAD.encode(token)
+}
+
+fn hash_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(digest(&SHA256, token.as_bytes()))
+}
+
+// comments get a small subset of markdown: emphasis, code, quotes, lists and links. Raw html is shown
+// as text, headings become paragraphs, images are replaced by their alt text and links only go to
+// http(s) urls, marked so they don't pass on any ranking
+pub(crate) fn render_markdown(markdown: &str) -> String {
+    // whether each open link was kept, so its end is dropped along with it
+    let mut links = Vec::new();
+    let events = Parser::new_ext(markdown, Options::ENABLE_STRIKETHROUGH).filter_map(|event| match event {
+        Event::Html(html) | Event::InlineHtml(html) => Some(Event::Text(html)),
+        Event::Start(Tag::HtmlBlock) | Event::End(TagEnd::HtmlBlock) => None,
+        Event::Start(Tag::Heading { .. }) => Some(Event::Start(Tag::Paragraph)),
+        Event::End(TagEnd::Heading(_)) => Some(Event::End(TagEnd::Paragraph)),
+        Event::Start(Tag::Image { .. }) | Event::End(TagEnd::Image) => None,
+        Event::Start(Tag::Link { dest_url, .. }) => {
+            let url = dest_url.to_ascii_lowercase();
+            let is_kept = url.starts_with("https://") || url.starts_with("http://");
+            links.push(is_kept);
+            is_kept.then(|| {
+                let anchor = format!(r#"<a href="{}" rel="nofollow ugc noopener">"#, escape(&dest_url));
+                Event::InlineHtml(anchor.into())
+            })
+        }
+        Event::End(TagEnd::Link) => links.pop().unwrap_or(false).then(|| Event::InlineHtml("</a>".into())),
+        event => Some(event),
+    });
+
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, events);
+    rendered
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+
+struct CommentView {
+    comment: Comment,
+    html: String,
+    depth: usize,
+}
+
+#[derive(Template)]
+#[template(path = "comments.html")]
+struct CommentsTemplate<'a> {
+    post: &'a str,
+    thread: Vec<CommentView>,
+    max_depth: usize,
+    // shared by every form in the fragment, see `spam::FormStamps`
+    stamp: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CommentsParams {
+    post: String,
+}
+
+// htmx fragment with the comments on a post and a form to add one. Empty when comments are disabled,
+// so the placeholder it replaces just goes away
+pub(crate) async fn get_comments(State(state): State<Arc<AppState>>, Query(params): Query<CommentsParams>) -> Response {
+    let config = &state.config.comments;
+    if !config.enabled {
+        return Html(String::new()).into_response();
+    }
+    let Some(post) = state.content.get(&params.post) else {
+        return handler_404().await.into_response();
+    };
+
+    let thread = state
+        .comments
+        .thread(&post.id)
+        .into_iter()
+        .map(|(comment, depth)| CommentView {
+            html: render_markdown(&comment.body),
+            comment,
+            depth,
+        })
+        .collect();
+    let comments = CommentsTemplate {
+        post: &post.id,
+        thread,
+        max_depth: config.max_depth,
+        stamp: state.comments.stamps.issue(),
+    };
+    Html(comments.render().unwrap()).into_response()
+}
+
+
+// a form sent back with what was entered and why it was refused, htmx only swaps in successful
+// responses so it comes with a 200
+#[derive(Template)]
+#[template(path = "comment_form.html")]
+struct CommentFormTemplate<'a> {
+    post: &'a str,
+    // 0 for a comment on the post itself
+    parent: u64,
+    author: &'a str,
+    body: &'a str,
+    error: &'a str,
+    stamp: String,
+}
+
+// shown in place of the form once a comment is in, with a form to edit it while that is allowed
+#[derive(Template)]
+#[template(path = "comment_submitted.html")]
+struct CommentSubmittedTemplate<'a> {
+    message: &'a str,
+    edit: Option<EditForm<'a>>,
+}
+
+struct EditForm<'a> {
+    id: u64,
+    token: &'a str,
+    body: &'a str,
+    until: DateTime<Utc>,
+}
+
+impl<'a> EditForm<'a> {
+    // `None` once the comment can't be edited any more
+    fn new(config: &CommentsConfig, comment: &'a Comment, token: &'a str, body: &'a str) -> Option<Self> {
+        let until = edit_deadline(config, comment);
+        let is_editable = matches!(comment.status, Status::Pending | Status::Approved);
+        (config.edit_window_secs > 0 && is_editable && Utc::now() < until).then_some(EditForm {
+            id: comment.id,
+            token,
+            body,
+            until,
+        })
+    }
+}
+
+fn thanks(config: &CommentsConfig) -> &'static str {
+    if config.moderate {
+        "Thanks! Your comment will show once it has been approved."
+    } else {
+        "Thanks! Your comment has been posted."
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SubmitComment {
+    post: String,
+    parent: Option<u64>,
+    #[serde(default)]
+    author: String,
+    body: String,
+    // when the form was shown, see `spam::FormStamps`
+    #[serde(default)]
+    stamp: String,
+    // the honeypot, hidden from people so only bots fill it in
+    #[serde(default)]
+    website: String,
+}
+
+// adds a comment once it is past the spam defences: the honeypot, a minimum time to fill in the form,
+// the link limit and the classifier. Comments are also rate limited per client by
+// `rate_limit.comments`
+pub(crate) async fn submit_comment(State(state): State<Arc<AppState>>, Form(form): Form<SubmitComment>) -> Response {
+    let config = &state.config.comments;
+    if !config.enabled || state.content.get(&form.post).is_none() {
+        return handler_404().await.into_response();
+    }
+
+    // bots are thanked as usual so they've no reason to try anything else
+    if !form.website.is_empty() {
+        metrics().comments_blocked.with_label_values(&["honeypot"]).inc();
+        tracing::debug!("Comment on {} caught by the honeypot.", form.post);
+        let submitted = CommentSubmittedTemplate {
+            message: thanks(config),
+            edit: None,
+        };
+        return Html(submitted.render().unwrap()).into_response();
+    }
+
+    let result = if state.comments.stamps.is_old_enough(&form.stamp, config.min_submit_secs) {
+        state.comments.submit(config, &form.post, form.parent, &form.author, &form.body)
+    } else {
+        // people sometimes get here too, e.g. with a form from before a restart, so they are asked
+        // to send it again with a fresh stamp
+        metrics().comments_blocked.with_label_values(&["too_fast"]).inc();
+        Err("That was quick! Please look over your comment and send it again.".to_string())
+    };
+
+    match result {
+        Ok((comment, token)) => {
+            persist(&state.comments).await;
+            metrics().comments.with_label_values(&["submitted"]).inc();
+            tracing::info!("Comment {} submitted on {}, {}.", comment.id, comment.post, comment.status.label());
+            let submitted = CommentSubmittedTemplate {
+                message: thanks(config),
+                edit: EditForm::new(config, &comment, &token, &comment.body),
+            };
+            Html(submitted.render().unwrap()).into_response()
+        }
+        Err(error) => {
+            let form = CommentFormTemplate {
+                post: &form.post,
+                parent: form.parent.unwrap_or(0),
+                author: &form.author,
+                body: &form.body,
+                error: &error,
+                stamp: state.comments.stamps.issue(),
+            };
+            Html(form.render().unwrap()).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EditComment {
+    id: u64,
+    token: String,
+    body: String,
+}
+
+pub(crate) async fn edit_comment(State(state): State<Arc<AppState>>, Form(form): Form<EditComment>) -> Response {
+    let config = &state.config.comments;
+    if !config.enabled {
+        return handler_404().await.into_response();
+    }
+
+    match state.comments.edit(config, form.id, &form.token, &form.body) {
+        Ok(comment) => {
+            persist(&state.comments).await;
+            metrics().comments.with_label_values(&["edited"]).inc();
+            let submitted = CommentSubmittedTemplate {
+                message: thanks(config),
+                edit: EditForm::new(config, &comment, &form.token, &comment.body),
+            };
+            Html(submitted.render().unwrap()).into_response()
+        }
+        Err(error) => {
+            // the form is shown again with the new body, as long as the token is good
+            let comment = state.comments.with_token(form.id, &form.token);
+            let submitted = CommentSubmittedTemplate {
+                message: &error,
+                edit: comment
+                    .as_ref()
+                    .and_then(|comment| EditForm::new(config, comment, &form.token, &form.body)),
+            };
+            Html(submitted.render().unwrap()).into_response()
+        }
+    }
+}
+
+struct QueuedComment {
+    comment: Comment,
+    // title of the post commented on, `None` if it has since been removed
+    post_title: Option<String>,
+    html: String,
+}
+
+#[derive(Template)]
+#[template(path = "moderation.html")]
+struct ModerationTemplate {
+    queue: Vec<QueuedComment>,
+    caught: Vec<QueuedComment>,
+    caught_limit: usize,
+}
+
+// admin page listing the comments awaiting moderation, and the spam the classifier caught so that
+// anything it got wrong can be approved
+pub(crate) async fn serve_moderation(State(state): State<Arc<AppState>>) -> Html<String> {
+    let queued = |comments: Vec<Comment>| -> Vec<QueuedComment> {
+        comments
+            .into_iter()
+            .map(|comment| QueuedComment {
+                post_title: state.content.get(&comment.post).map(|post| post.title.clone()),
+                html: render_markdown(&comment.body),
+                comment,
+            })
+            .collect()
+    };
+    let moderation = ModerationTemplate {
+        queue: queued(state.comments.queue()),
+        caught: queued(state.comments.caught(CAUGHT_SPAM_SHOWN)),
+        caught_limit: CAUGHT_SPAM_SHOWN,
+    };
+    Html(moderation.render().unwrap())
+}
+
+// approves, rejects or marks a comment as spam, answering the moderation page's htmx request with
+// the row to show in its place
+pub(crate) async fn moderate_comment(
+    State(state): State<Arc<AppState>>,
+    Path((id, action)): Path<(u64, String)>,
+) -> Response {
+    let Some(status) = Status::from_action(&action) else {
+        return handler_404().await.into_response();
+    };
+    let Some(comment) = state.comments.moderate(id, status) else {
+        return handler_404().await.into_response();
+    };
+    persist(&state.comments).await;
+    metrics().comments.with_label_values(&[status.label()]).inc();
+    tracing::info!("Comment {} on {} marked {}.", comment.id, comment.post, status.label());
+
+    Html(format!(
+        r#"<tr id="comment-{}"><td colspan="5">Comment {} marked {}.</td></tr>"#,
+        comment.id,
+        comment.id,
+        status.label()
+    ))
+    .into_response()
+}
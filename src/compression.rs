@@ -0,0 +1,152 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use axum::{
+    body::HttpBody,
+    http::{header, Response},
+};
+use flate2::{write::GzEncoder, Compression};
+use tower_http::compression::{
+    predicate::{And, Predicate, SizeAbove},
+    CompressionLayer,
+};
+use walkdir::WalkDir;
+
+use crate::config::CompressionConfig;
+
+
+
+type Encoder = fn(&[u8]) -> io::Result<Vec<u8>>;
+
+// file extension of each precompressed variant `ServeDir` looks for, with the encoder producing it
+const PRECOMPRESSED: [(&str, Encoder); 3] = [("gz", gzip), ("br", brotli), ("zst", zstd)];
+
+// problems with the compression settings, reported together by `Config::validate`
+pub(crate) fn validate(config: &CompressionConfig) -> Vec<String> {
+    config
+        .exclude_content_types
+        .iter()
+        .filter(|content_type| !content_type.contains('/'))
+        .map(|content_type| {
+            format!(
+                "compression.exclude_content_types: '{}' is not a content type or prefix such as video/",
+                content_type
+            )
+        })
+        .collect()
+}
+
+// content types are matched by prefix, ignoring case and any parameters after the type
+fn is_excluded(content_type: &str, exclude_content_types: &[String]) -> bool {
+    let content_type = content_type.trim().to_ascii_lowercase();
+    exclude_content_types
+        .iter()
+        .any(|excluded| content_type.starts_with(&excluded.to_ascii_lowercase()))
+}
+
+// skips responses in formats that are already compressed. Responses that already have a
+// `Content-Encoding`, e.g. precompressed assets, are always left alone by `CompressionLayer`
+#[derive(Clone)]
+pub(crate) struct CompressibleContentType {
+    enabled: bool,
+    exclude_content_types: Arc<[String]>,
+}
+
+impl Predicate for CompressibleContentType {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: HttpBody,
+    {
+        if !self.enabled {
+            return false;
+        }
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        !is_excluded(content_type, &self.exclude_content_types)
+    }
+}
+
+// compresses responses with whichever of zstd, brotli or gzip the client prefers through
+// `Accept-Encoding`, see `config::CompressionConfig`
+pub(crate) fn layer(config: &CompressionConfig) -> CompressionLayer<And<SizeAbove, CompressibleContentType>> {
+    CompressionLayer::new().compress_when(SizeAbove::new(config.min_size).and(CompressibleContentType {
+        enabled: config.enabled,
+        exclude_content_types: config.exclude_content_types.clone().into(),
+    }))
+}
+
+// writes `.gz`, `.br` and `.zst` variants next to every compressible file in the static directory, for
+// `ServeDir` to send as is to clients accepting them. Meant to run at build time, so the slowest and
+// smallest settings are used. Returns the number of files compressed
+pub(crate) fn precompress(dir: &Path, config: &CompressionConfig) -> Result<usize, String> {
+    let mut compressed = 0;
+
+    for entry in WalkDir::new(dir).follow_links(true) {
+        let entry = entry.map_err(|err| format!("failed to read {}: {}", dir.display(), err))?;
+        let path = entry.path();
+        let is_variant = path
+            .extension()
+            .is_some_and(|extension| PRECOMPRESSED.iter().any(|(variant, _)| extension == *variant));
+        if !entry.file_type().is_file() || is_variant {
+            continue;
+        }
+
+        let content_type = mime_guess::from_path(path).first_or_octet_stream();
+        if is_excluded(content_type.essence_str(), &config.exclude_content_types) {
+            continue;
+        }
+        let contents = fs::read(path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        if contents.len() < usize::from(config.min_size) {
+            continue;
+        }
+
+        for (extension, encode) in PRECOMPRESSED {
+            let mut variant = path.as_os_str().to_owned();
+            variant.push(".");
+            variant.push(extension);
+            let encoded = encode(&contents).map_err(|err| format!("failed to compress {}: {}", path.display(), err))?;
+
+            // a variant that isn't smaller would only cost the client, so the original is served
+            // instead. One left over from an earlier run is removed as it may be out of date
+            let result = if encoded.len() < contents.len() {
+                fs::write(&variant, encoded)
+            } else {
+                match fs::remove_file(&variant) {
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                    result => result,
+                }
+            };
+            result.map_err(|err| format!("failed to write {}: {}", Path::new(&variant).display(), err))?;
+        }
+        compressed += 1;
+    }
+
+    Ok(compressed)
+}
+
+fn gzip(contents: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(contents)?;
+    encoder.finish()
+}
+
+fn brotli(contents: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    let params = brotli::enc::BrotliEncoderParams {
+        quality: 11,
+        ..Default::default()
+    };
+    brotli::BrotliCompress(&mut &contents[..], &mut encoded, &params)?;
+    Ok(encoded)
+}
+
+fn zstd(contents: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::encode_all(contents, 19)
+}
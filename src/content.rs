@@ -0,0 +1,169 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use pulldown_cmark::{html, Options, Parser};
+use serde::Deserialize;
+
+
+
+// posts are markdown files with a TOML front matter block, e.g.
+//
+//   +++
+//   title = "Hello world"
+//   date = 2024-05-01
+//   updated = 2024-05-03  # optional
+//   +++
+//
+//   Post body in markdown...
+//
+// the file stem is used as the post id
+const FRONT_MATTER_DELIMITER: &str = "+++";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FrontMatter {
+    title: String,
+    date: toml::value::Datetime,
+    // last edit, defaults to when the file was last modified
+    updated: Option<toml::value::Datetime>,
+    #[serde(default)]
+    draft: bool,
+}
+
+#[derive(PartialEq)]
+pub(crate) struct BlogPost {
+    pub(crate) id: String,
+    pub(crate) title: String,
+    // rendered html
+    pub(crate) content: String,
+    pub(crate) published: DateTime<Utc>,
+    // sent as `Last-Modified`
+    pub(crate) updated: DateTime<Utc>,
+}
+
+// snapshot of every published post, newest first
+pub(crate) struct Content {
+    posts: Vec<Arc<BlogPost>>,
+}
+
+impl Content {
+    pub(crate) fn load(dir: &Path) -> Result<Self, String> {
+        let entries = fs::read_dir(dir)
+            .map_err(|err| format!("failed to read content directory {}: {}", dir.display(), err))?;
+
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "md"))
+            .collect();
+        paths.sort();
+
+        // a single broken post fails the whole load so a reload never half applies
+        let mut posts = Vec::new();
+        for path in paths {
+            if let Some(post) = load_post(&path).map_err(|err| format!("{}: {}", path.display(), err))? {
+                posts.push(Arc::new(post));
+            }
+        }
+        posts.sort_by(|a, b| b.published.cmp(&a.published).then_with(|| a.id.cmp(&b.id)));
+
+        Ok(Content { posts })
+    }
+
+    pub(crate) fn posts(&self) -> &[Arc<BlogPost>] {
+        &self.posts
+    }
+
+    pub(crate) fn latest(&self) -> Option<Arc<BlogPost>> {
+        self.posts.first().cloned()
+    }
+
+    pub(crate) fn get(&self, id: &str) -> Option<Arc<BlogPost>> {
+        self.posts.iter().find(|post| post.id == id).cloned()
+    }
+
+    // the next older post, used to drive the infinite scroll
+    pub(crate) fn after(&self, id: &str) -> Option<Arc<BlogPost>> {
+        let position = self.posts.iter().position(|post| post.id == id)?;
+        self.posts.get(position + 1).cloned()
+    }
+
+    // the post `/blog-post` shows after the given one, or the latest when there is none
+    pub(crate) fn next(&self, id: Option<&str>) -> Option<Arc<BlogPost>> {
+        match id {
+            None => self.latest(),
+            Some(id) => self.after(id),
+        }
+    }
+
+    // ids of the posts added, edited or removed since the previous snapshot
+    pub(crate) fn changed_since(&self, previous: &Content) -> HashSet<String> {
+        let by_id = |content: &Content| -> HashMap<String, Arc<BlogPost>> {
+            content.posts.iter().map(|post| (post.id.clone(), post.clone())).collect()
+        };
+        let (before, after) = (by_id(previous), by_id(self));
+        before
+            .keys()
+            .chain(after.keys())
+            .filter(|id| before.get(*id) != after.get(*id))
+            .cloned()
+            .collect()
+    }
+}
+
+
+fn load_post(path: &Path) -> Result<Option<BlogPost>, String> {
+    let raw = fs::read_to_string(path).map_err(|err| err.to_string())?;
+
+    let (front_matter, body) = raw
+        .trim_start()
+        .strip_prefix(FRONT_MATTER_DELIMITER)
+        .and_then(|rest| rest.split_once(&format!("\n{}", FRONT_MATTER_DELIMITER)))
+        .ok_or_else(|| format!("missing {} delimited front matter", FRONT_MATTER_DELIMITER))?;
+    let front_matter: FrontMatter = toml::from_str(front_matter).map_err(|err| err.to_string())?;
+    if front_matter.draft {
+        return Ok(None);
+    }
+
+    // unwrap is fine as only files with a stem get this far
+    let id = path.file_stem().unwrap().to_string_lossy().into_owned();
+    let published = parse_date(&front_matter.date)?;
+    let updated = match &front_matter.updated {
+        Some(updated) => parse_date(updated)?,
+        None => fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|err| err.to_string())?
+            .into(),
+    };
+
+    let mut content = String::new();
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS;
+    html::push_html(&mut content, Parser::new_ext(body, options));
+
+    Ok(Some(BlogPost {
+        id,
+        title: front_matter.title,
+        content,
+        published,
+        updated,
+    }))
+}
+
+// accepts either a full RFC 3339 datetime or a bare date, taken as midnight UTC
+fn parse_date(date: &toml::value::Datetime) -> Result<DateTime<Utc>, String> {
+    let date = date.to_string();
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(&date) {
+        return Ok(datetime.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .map_err(|_| format!("'{}' is not a date or RFC 3339 datetime", date))
+}
@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use axum::http::{request::Parts, HeaderName, HeaderValue, Method, Uri};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::config::{Config, CorsConfig};
+
+
+
+// an allowed origin as given in `cors.allow_origins`
+enum OriginPattern {
+    // `*`, any origin
+    Any,
+    // e.g. `https://example.com`
+    Exact(HeaderValue),
+    // e.g. `https://*.example.com`, matching any subdomain but not example.com itself
+    Subdomain { scheme: String, suffix: String },
+}
+
+fn parse_origin(origin: &str) -> Result<OriginPattern, String> {
+    if origin == "*" {
+        return Ok(OriginPattern::Any);
+    }
+
+    let invalid = || format!("'{}' is not a valid origin, expected e.g. https://example.com or https://*.example.com", origin);
+    let (scheme, authority) = origin.split_once("://").ok_or_else(invalid)?;
+    if scheme != "http" && scheme != "https" {
+        return Err(invalid());
+    }
+    let (wildcard, authority) = match authority.strip_prefix("*.") {
+        Some(authority) => (true, authority),
+        None => (false, authority),
+    };
+    // browsers send origins without a path or credentials, so one with either could never match
+    let uri: Uri = format!("{}://{}", scheme, authority).parse().map_err(|_| invalid())?;
+    if uri.host().is_none_or(str::is_empty) || uri.path() != "/" || authority.contains('/') || authority.contains('@') {
+        return Err(invalid());
+    }
+
+    let authority = authority.to_ascii_lowercase();
+    if wildcard {
+        Ok(OriginPattern::Subdomain {
+            scheme: format!("{}://", scheme),
+            suffix: format!(".{}", authority),
+        })
+    } else {
+        // unwrap is fine as the uri parsed, so is plain ascii
+        Ok(OriginPattern::Exact(HeaderValue::from_str(&format!("{}://{}", scheme, authority)).unwrap()))
+    }
+}
+
+impl OriginPattern {
+    fn matches(&self, origin: &HeaderValue) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(allowed) => allowed.as_bytes().eq_ignore_ascii_case(origin.as_bytes()),
+            OriginPattern::Subdomain { scheme, suffix } => {
+                let Ok(origin) = origin.to_str() else {
+                    return false;
+                };
+                let origin = origin.to_ascii_lowercase();
+                origin
+                    .strip_prefix(scheme.as_str())
+                    .and_then(|host| host.strip_suffix(suffix.as_str()))
+                    .is_some_and(|subdomain| {
+                        !subdomain.is_empty()
+                            && subdomain
+                                .split('.')
+                                .all(|label| !label.is_empty() && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-'))
+                    })
+            }
+        }
+    }
+}
+
+// problems with the CORS settings, reported together by `Config::validate`
+pub(crate) fn validate(config: &CorsConfig) -> Vec<String> {
+    let mut errors = Vec::new();
+    for origin in &config.allow_origins {
+        if let Err(err) = parse_origin(origin) {
+            errors.push(format!("cors.allow_origins: {}", err));
+        }
+    }
+    for method in &config.allow_methods {
+        if Method::from_bytes(method.as_bytes()).is_err() {
+            errors.push(format!("cors.allow_methods: '{}' is not a valid method", method));
+        }
+    }
+    for name in &config.allow_headers {
+        if HeaderName::try_from(name.as_str()).is_err() {
+            errors.push(format!("cors.allow_headers: '{}' is not a valid header name", name));
+        }
+    }
+    errors
+}
+
+// the public url is always allowed alongside the configured origins. Unwraps are fine as everything
+// is checked by `Config::validate`
+pub(crate) fn layer(config: &Config) -> CorsLayer {
+    let cors = &config.cors;
+
+    let mut patterns: Vec<OriginPattern> = cors
+        .allow_origins
+        .iter()
+        .map(|origin| parse_origin(origin).unwrap())
+        .collect();
+    if let Ok(public_origin) = parse_origin(&config.public_origin()) {
+        patterns.push(public_origin);
+    }
+
+    let allow_origin = if patterns.iter().any(|pattern| matches!(pattern, OriginPattern::Any)) {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::predicate(move |origin: &HeaderValue, _: &Parts| {
+            patterns.iter().any(|pattern| pattern.matches(origin))
+        })
+    };
+    let methods: Vec<Method> = cors
+        .allow_methods
+        .iter()
+        .map(|method| Method::from_bytes(method.as_bytes()).unwrap())
+        .collect();
+    let headers: Vec<HeaderName> = cors
+        .allow_headers
+        .iter()
+        .map(|name| HeaderName::try_from(name.as_str()).unwrap())
+        .collect();
+
+    let layer = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(methods)
+        .allow_headers(headers);
+    match cors.max_age_secs {
+        0 => layer,
+        max_age => layer.max_age(Duration::from_secs(max_age)),
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    path::Path,
+    sync::Arc,
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Router,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use h3::{error::StreamError, quic::BidiStream, server::RequestStream};
+use http_body_util::BodyExt;
+use quinn::crypto::rustls::QuicServerConfig;
+use tower::ServiceExt;
+
+use crate::tls;
+
+
+
+// request bodies are buffered before being handed to the app, anything larger is refused
+const MAX_REQUEST_BODY: usize = 2 * 1024 * 1024;
+
+// QUIC only ever negotiates h3, so the ALPN list is fixed
+pub(crate) fn server_config(cert: &Path, key: &Path) -> Result<quinn::ServerConfig, String> {
+    let tls_config = tls::server_config(cert, key, vec![b"h3".to_vec()])?;
+    let crypto = QuicServerConfig::try_from(tls_config).map_err(|err| err.to_string())?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+pub(crate) fn bind(address: &str, cert: &Path, key: &Path) -> Result<quinn::Endpoint, String> {
+    let socket_addr = address
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("failed to resolve {}", address))?;
+    let config = server_config(cert, key)?;
+    quinn::Endpoint::server(config, socket_addr).map_err(|err| format!("failed to bind {}/udp: {}", address, err))
+}
+
+// serves the app over HTTP/3 until the endpoint is closed
+pub(crate) async fn serve(endpoint: quinn::Endpoint, app: Router) {
+    while let Some(incoming) = endpoint.accept().await {
+        let app = app.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_connection(incoming, app).await {
+                tracing::debug!("HTTP/3 connection closed. Error: {}", err);
+            }
+        });
+    }
+}
+
+async fn serve_connection(incoming: quinn::Incoming, app: Router) -> Result<(), String> {
+    let connection = incoming.await.map_err(|err| err.to_string())?;
+    let remote_addr = connection.remote_address();
+    let mut connection = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection))
+        .await
+        .map_err(|err| err.to_string())?;
+
+    loop {
+        match connection.accept().await {
+            Ok(Some(resolver)) => {
+                let app = app.clone();
+                tokio::spawn(async move {
+                    let result = match resolver.resolve_request().await {
+                        Ok((request, stream)) => serve_request(app, remote_addr, request, stream).await,
+                        Err(err) => Err(err),
+                    };
+                    // a client closing the stream normally isn't worth reporting
+                    match result {
+                        Err(err) if !err.is_h3_no_error() => {
+                            tracing::debug!("HTTP/3 request failed. Error: {}", err);
+                        }
+                        _ => {}
+                    }
+                });
+            }
+            Ok(None) => return Ok(()),
+            Err(err) if err.is_h3_no_error() => return Ok(()),
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+}
+
+// bridges a single request stream to the app. Requests get the same `ConnectInfo` as over TCP so
+// rate limiting and the access log see the client address, and h3 sets the request version to
+// HTTP/3 so the `http_request` span reports it
+async fn serve_request<S>(
+    app: Router,
+    remote_addr: SocketAddr,
+    request: axum::http::Request<()>,
+    stream: RequestStream<S, Bytes>,
+) -> Result<(), StreamError>
+where
+    S: BidiStream<Bytes>,
+{
+    let (mut send, mut recv) = stream.split();
+
+    let mut body = BytesMut::new();
+    let mut too_large = false;
+    while let Some(chunk) = recv.recv_data().await? {
+        if body.len() + chunk.remaining() > MAX_REQUEST_BODY {
+            too_large = true;
+            break;
+        }
+        body.put(chunk);
+    }
+
+    let response = if too_large {
+        StatusCode::PAYLOAD_TOO_LARGE.into_response()
+    } else {
+        let (mut parts, ()) = request.into_parts();
+        parts.extensions.insert(ConnectInfo(remote_addr));
+        let request = Request::from_parts(parts, Body::from(body.freeze()));
+        match app.oneshot(request).await {
+            Ok(response) => response,
+            Err(err) => match err {},
+        }
+    };
+
+    let (parts, mut body) = response.into_parts();
+    send.send_response(Response::from_parts(parts, ())).await?;
+    while let Some(frame) = body.frame().await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(err) => {
+                tracing::error!("Failed to stream HTTP/3 response body. Error: {:#?}", err);
+                break;
+            }
+        };
+        match frame.into_data() {
+            Ok(data) => send.send_data(data).await?,
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    send.send_trailers(trailers).await?;
+                }
+            }
+        }
+    }
+    send.finish().await
+}
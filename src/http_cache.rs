@@ -0,0 +1,249 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http_body_util::BodyExt;
+use hyper::body::Frame;
+use ring::digest::{digest, SHA256};
+
+use crate::config::CacheControlConfig;
+
+
+
+// rendered pages are far smaller, anything bigger isn't worth holding in memory for its ETag
+const MAX_ETAG_BODY_BYTES: usize = 1024 * 1024;
+
+// marks responses streamed from a file, which are never buffered for an ETag. `ServeDir` revalidates
+// them with `Last-Modified` instead
+#[derive(Clone, Copy)]
+pub(crate) struct Streamed;
+
+// problems with the cache control settings, reported together by `Config::validate`
+pub(crate) fn validate(config: &CacheControlConfig) -> Vec<String> {
+    let mut errors = Vec::new();
+    let policies = [
+        ("default", &config.default),
+        ("personalised", &config.personalised),
+        ("static_files", &config.static_files),
+    ]
+        .into_iter()
+        .map(|(setting, policy)| (setting.to_string(), policy))
+        .chain(config.routes.iter().map(|(route, policy)| (format!("routes.\"{}\"", route), policy)));
+    for (setting, policy) in policies {
+        if policy.trim().is_empty() || HeaderValue::from_str(policy).is_err() {
+            errors.push(format!("cache_control.{}: '{}' is not a valid Cache-Control value", setting, policy));
+        }
+    }
+    for route in config.routes.keys() {
+        if !route.starts_with('/') {
+            errors.push(format!("cache_control.routes: '{}' is not a path, expected e.g. /blog-post", route));
+        }
+    }
+    errors
+}
+
+// `Cache-Control` values by request path, see `config::CacheControlConfig`. Unwraps are fine as the
+// values are checked by `Config::validate`
+pub(crate) struct CachePolicies {
+    default: HeaderValue,
+    routes: HashMap<String, HeaderValue>,
+}
+
+impl CachePolicies {
+    // `personalised_routes` get `cache_control.personalised` unless `cache_control.routes` says otherwise
+    pub(crate) fn new(config: &CacheControlConfig, personalised_routes: &[&str]) -> Arc<Self> {
+        let personalised = HeaderValue::from_str(&config.personalised).unwrap();
+        let mut routes: HashMap<String, HeaderValue> = personalised_routes
+            .iter()
+            .map(|route| (route.to_string(), personalised.clone()))
+            .collect();
+        for (route, policy) in &config.routes {
+            routes.insert(route.clone(), HeaderValue::from_str(policy).unwrap());
+        }
+        Arc::new(CachePolicies {
+            default: HeaderValue::from_str(&config.default).unwrap(),
+            routes,
+        })
+    }
+}
+
+// adds the route's `Cache-Control` to successful responses. Rendered pages also get a strong `ETag`
+// of their body, and `If-None-Match` or `If-Modified-Since` matching the page as it is now is
+// answered with a bodiless 304. Sits outside compression so each encoding has its own ETag. `Streamed`
+// responses and bodies over `MAX_ETAG_BODY_BYTES` are passed on without one
+pub(crate) async fn http_cache(State(policies): State<Arc<CachePolicies>>, request: Request, next: Next) -> Response {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return next.run(request).await;
+    }
+    let policy = policies.routes.get(request.uri().path()).unwrap_or(&policies.default).clone();
+    let mut conditions = HeaderMap::new();
+    for name in [header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE] {
+        if let Some(value) = request.headers().get(&name) {
+            conditions.insert(name, value.clone());
+        }
+    }
+
+    let mut response = next.run(request).await;
+    if response.status() != StatusCode::OK && response.status() != StatusCode::NOT_MODIFIED {
+        return response;
+    }
+    response.headers_mut().entry(header::CACHE_CONTROL).or_insert(policy);
+    if response.status() != StatusCode::OK
+        || response.headers().contains_key(header::ETAG)
+        || response.extensions().get::<Streamed>().is_some()
+        || !is_html(&response)
+    {
+        return response;
+    }
+
+    let (mut parts, mut body) = response.into_parts();
+    // compression hides the length, so the body is read up to the limit to find out
+    let mut buffered = Vec::new();
+    while buffered.len() <= MAX_ETAG_BODY_BYTES {
+        match body.frame().await {
+            Some(Ok(frame)) => {
+                if let Ok(data) = frame.into_data() {
+                    buffered.extend_from_slice(&data);
+                }
+            }
+            Some(Err(err)) => {
+                tracing::error!("Failed to buffer response for its ETag. Error: {:#?}", err);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+            None => break,
+        }
+    }
+    if buffered.len() > MAX_ETAG_BODY_BYTES {
+        let body = Resumed {
+            buffered: Some(buffered.into()),
+            rest: body,
+        };
+        return Response::from_parts(parts, Body::new(body));
+    }
+    let body = Bytes::from(buffered);
+    parts.headers.insert(header::ETAG, etag(&body));
+
+    if is_not_modified(&conditions, &parts.headers) {
+        parts.status = StatusCode::NOT_MODIFIED;
+        // a 304 only repeats the validators and caching headers, the client already has the rest
+        for name in [header::CONTENT_LENGTH, header::CONTENT_TYPE, header::CONTENT_ENCODING] {
+            parts.headers.remove(name);
+        }
+        return Response::from_parts(parts, Body::empty());
+    }
+    Response::from_parts(parts, Body::from(body))
+}
+
+// a body too big for an ETag, sent on from where buffering it stopped
+struct Resumed {
+    buffered: Option<Bytes>,
+    rest: Body,
+}
+
+impl HttpBody for Resumed {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        match self.buffered.take() {
+            Some(buffered) => Poll::Ready(Some(Ok(Frame::data(buffered)))),
+            None => Pin::new(&mut self.rest).poll_frame(cx),
+        }
+    }
+}
+
+fn is_html(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/html"))
+}
+
+fn etag(body: &[u8]) -> HeaderValue {
+    // truncated, 128 bits is plenty to tell versions of a page apart. Unwrap is fine as base64 is
+    // plain ascii
+    let hash = digest(&SHA256, body);
+    HeaderValue::from_str(&format!("\"{}\"", URL_SAFE_NO_PAD.encode(&hash.as_ref()[..16]))).unwrap()
+}
+
+// evaluated as in RFC 9110 section 13.2.2, `If-Modified-Since` only counts when there is no
+// `If-None-Match`. Entity tags are compared weakly, as is required for GET and HEAD
+fn is_not_modified(request: &HeaderMap, response: &HeaderMap) -> bool {
+    if let Some(if_none_match) = request.get(header::IF_NONE_MATCH) {
+        let Some(etag) = response.get(header::ETAG).and_then(|value| value.to_str().ok()) else {
+            return false;
+        };
+        let etag = etag.trim_start_matches("W/");
+        return if_none_match.to_str().is_ok_and(|tags| {
+            tags.split(',')
+                .map(|tag| tag.trim())
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+        });
+    }
+
+    let if_modified_since = request
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok());
+    let last_modified = response
+        .get(header::LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok());
+    match (if_modified_since, last_modified) {
+        (Some(if_modified_since), Some(last_modified)) => last_modified <= if_modified_since,
+        _ => false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, response::Html, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn router() -> Router {
+        let policies = CachePolicies::new(&CacheControlConfig::default(), &["/reactions"]);
+        Router::new()
+            .route("/", get(|| async { Html("<p>hello</p>") }))
+            .route("/reactions", get(|| async { Html("<p>you chose 👍</p>") }))
+            .route("/huge", get(|| async { Html("x".repeat(MAX_ETAG_BODY_BYTES + 1)) }))
+            .layer(middleware::from_fn_with_state(policies, http_cache))
+    }
+
+    async fn get_page(path: &str) -> Response {
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        router().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn personalised_fragments_stay_out_of_shared_caches() {
+        let page = get_page("/").await;
+        assert_eq!(page.headers()[header::CACHE_CONTROL], "public, no-cache");
+        let reactions = get_page("/reactions").await;
+        assert_eq!(reactions.headers()[header::CACHE_CONTROL], "private, no-store");
+    }
+
+    #[tokio::test]
+    async fn only_small_bodies_are_buffered_for_an_etag() {
+        assert!(get_page("/").await.headers().contains_key(header::ETAG));
+
+        let huge = get_page("/huge").await;
+        assert!(!huge.headers().contains_key(header::ETAG));
+        let body = huge.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body.len(), MAX_ETAG_BODY_BYTES + 1);
+    }
+}
@@ -0,0 +1,339 @@
+use std::{
+    fs,
+    future::Future,
+    io::BufReader,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap, HeaderValue, Request, Response, StatusCode},
+};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::TlsConnector;
+use url::{Host, Position, Url};
+
+use crate::config::HttpClientConfig;
+
+
+
+// tried in order when `http_client.ca_file` isn't set
+const CA_BUNDLES: &[&str] = &[
+    // debian, ubuntu, alpine and arch
+    "/etc/ssl/certs/ca-certificates.crt",
+    // fedora and rhel
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    // macos and openbsd
+    "/etc/ssl/cert.pem",
+    // opensuse
+    "/etc/ssl/ca-bundle.pem",
+];
+
+const MAX_REDIRECTS: usize = 5;
+
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+pub(crate) type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// a response, read in full
+pub(crate) struct Fetched {
+    pub(crate) status: StatusCode,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Bytes,
+    // where the response came from, after any redirects
+    pub(crate) url: Url,
+}
+
+impl Fetched {
+    // whether the body is html, taking a response without a content type to be
+    pub(crate) fn is_html(&self) -> bool {
+        self.headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_none_or(|content_type| content_type.to_ascii_lowercase().contains("html"))
+    }
+}
+
+// requests to other sites. A trait so whatever makes them, e.g. verifying webmentions, can be pointed
+// at a stub server rather than the internet
+pub(crate) trait HttpClient: Send + Sync {
+    // sends a request to the absolute url in its uri, without following redirects
+    fn send(&self, request: Request<Bytes>) -> BoxFuture<'_, Result<Fetched, String>>;
+}
+
+// GETs a url, following redirects
+pub(crate) async fn get(client: &dyn HttpClient, url: &Url, accept: &str) -> Result<Fetched, String> {
+    let mut url = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        let request = Request::get(url.as_str())
+            .header(header::ACCEPT, accept)
+            .body(Bytes::new())
+            .map_err(|err| format!("bad request to {}: {}", url, err))?;
+        let fetched = client.send(request).await?;
+        if !fetched.status.is_redirection() {
+            return Ok(fetched);
+        }
+
+        let location = fetched
+            .headers
+            .get(header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| format!("{} redirected without a location", url))?;
+        url = url.join(location).map_err(|err| format!("{} redirected to '{}': {}", url, location, err))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("redirected to {}, which isn't http(s)", url));
+        }
+    }
+    Err(format!("gave up on {} after {} redirects", url, MAX_REDIRECTS))
+}
+
+// POSTs a form to a url
+pub(crate) async fn post_form(client: &dyn HttpClient, url: &Url, fields: &[(&str, &str)]) -> Result<Fetched, String> {
+    let body = url::form_urlencoded::Serializer::new(String::new()).extend_pairs(fields).finish();
+    let request = Request::post(url.as_str())
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Bytes::from(body))
+        .map_err(|err| format!("bad request to {}: {}", url, err))?;
+    client.send(request).await
+}
+
+// a connection per request over HTTP/1.1, plenty for the odd request to another site
+pub(crate) struct HyperClient {
+    tls: TlsConnector,
+    timeout: Duration,
+    max_response_bytes: usize,
+    allow_private_addresses: bool,
+}
+
+impl HyperClient {
+    pub(crate) fn new(config: &HttpClientConfig) -> Result<Arc<Self>, String> {
+        let roots = load_roots(config.ca_file.as_deref())?;
+        // only ring is compiled in, see `tls::server_config`
+        let mut tls = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|err| format!("failed to set up the http client: {}", err))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Arc::new(HyperClient {
+            tls: TlsConnector::from(Arc::new(tls)),
+            timeout: Duration::from_secs(config.timeout_secs),
+            max_response_bytes: config.max_response_bytes,
+            allow_private_addresses: config.allow_private_addresses,
+        }))
+    }
+
+    async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, String> {
+        let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|err| format!("failed to resolve {}: {}", host, err))?
+            .collect();
+        // checked once resolved, so a public name for a private address is refused too
+        if !self.allow_private_addresses {
+            if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())) {
+                return Err(format!("refused to connect to {} at private address {}", host, address.ip()));
+            }
+        }
+
+        let mut last_error = format!("{} has no addresses", host);
+        for address in addresses {
+            match TcpStream::connect(address).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_error = format!("failed to connect to {} at {}: {}", host, address, err),
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn request(&self, request: Request<Bytes>) -> Result<Fetched, String> {
+        let url = Url::parse(&request.uri().to_string()).map_err(|err| format!("bad url {}: {}", request.uri(), err))?;
+        let host = match url.host() {
+            Some(Host::Domain(domain)) => domain.to_string(),
+            Some(Host::Ipv4(ip)) => ip.to_string(),
+            Some(Host::Ipv6(ip)) => ip.to_string(),
+            None => return Err(format!("{} has no host", url)),
+        };
+        let port = url.port_or_known_default().ok_or_else(|| format!("{} has no port", url))?;
+
+        // sent in origin form, with the authority as the host header
+        let (mut parts, body) = request.into_parts();
+        parts.uri = url[Position::BeforePath..Position::AfterQuery]
+            .parse()
+            .map_err(|err| format!("bad url {}: {}", url, err))?;
+        let authority = HeaderValue::from_str(&url[Position::BeforeHost..Position::AfterPort])
+            .map_err(|err| format!("bad url {}: {}", url, err))?;
+        parts.headers.insert(header::HOST, authority);
+        parts.headers.insert(header::CONNECTION, HeaderValue::from_static("close"));
+        parts.headers.entry(header::USER_AGENT).or_insert(HeaderValue::from_static(USER_AGENT));
+        let request = Request::from_parts(parts, Full::new(body));
+
+        let stream = self.connect(&host, port).await?;
+        let response = match url.scheme() {
+            "https" => {
+                let server_name =
+                    ServerName::try_from(host.clone()).map_err(|err| format!("bad host {}: {}", host, err))?;
+                let stream = self
+                    .tls
+                    .connect(server_name, stream)
+                    .await
+                    .map_err(|err| format!("TLS handshake with {} failed: {}", host, err))?;
+                send_http1(stream, request).await
+            }
+            "http" => send_http1(stream, request).await,
+            scheme => return Err(format!("{} is neither http nor https", scheme)),
+        }
+        .map_err(|err| format!("request to {} failed: {}", url, err))?;
+
+        let (parts, body) = response.into_parts();
+        let body = Limited::new(body, self.max_response_bytes)
+            .collect()
+            .await
+            .map_err(|err| format!("failed to read the response from {}: {}", url, err))?
+            .to_bytes();
+        Ok(Fetched {
+            status: parts.status,
+            headers: parts.headers,
+            body,
+            url,
+        })
+    }
+}
+
+impl HttpClient for HyperClient {
+    fn send(&self, request: Request<Bytes>) -> BoxFuture<'_, Result<Fetched, String>> {
+        Box::pin(async move {
+            let uri = request.uri().clone();
+            tokio::time::timeout(self.timeout, self.request(request))
+                .await
+                .map_err(|_| format!("request to {} timed out", uri))?
+        })
+    }
+}
+
+async fn send_http1<S>(stream: S, request: Request<Full<Bytes>>) -> Result<Response<Incoming>, hyper::Error>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    // drives the connection until the response is read, the server then closes it
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            tracing::debug!("Outgoing connection failed. Error: {}", err);
+        }
+    });
+    sender.send_request(request).await
+}
+
+fn load_roots(ca_file: Option<&Path>) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    let path = match ca_file.or_else(|| CA_BUNDLES.iter().map(Path::new).find(|path| path.is_file())) {
+        Some(path) => path,
+        None => {
+            tracing::warn!("No CA bundle found, requests over https will fail. Set http_client.ca_file to one.");
+            return Ok(roots);
+        }
+    };
+
+    let describe = |err: String| format!("failed to load CA bundle {}: {}", path.display(), err);
+    let certs = fs::File::open(path)
+        .map_err(|err| err.to_string())
+        .and_then(|file| {
+            rustls_pemfile::certs(&mut BufReader::new(file))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|err| err.to_string())
+        })
+        .map_err(describe)?;
+    roots.add_parsable_certificates(certs);
+    if roots.is_empty() {
+        return Err(describe("no certificates found".to_string()));
+    }
+    Ok(roots)
+}
+
+// whether an address is on the internet, rather than this host or a private network
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            let is_shared = first == 100 && (second & 0xc0) == 64;
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                || is_shared)
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                let is_unique_local = (first & 0xfe00) == 0xfc00;
+                let is_link_local = (first & 0xffc0) == 0xfe80;
+                !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || is_unique_local || is_link_local)
+            }
+        },
+    }
+}
+
+// the IPv4 address an IPv6 one leads to, for IPv4-mapped `::ffff:a.b.c.d` and IPv4-compatible `::a.b.c.d`
+// addresses, NAT64 `64:ff9b::a.b.c.d` and 6to4 `2002:aabb:ccdd::`
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let from_segments = |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+    match segments {
+        [0, 0, 0, 0, 0, 0xffff, high, low] | [0, 0, 0, 0, 0, 0, high, low] => Some(from_segments(high, low)),
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(from_segments(high, low)),
+        [0x2002, high, low, ..] => Some(from_segments(high, low)),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_internet_addresses_are_public() {
+        let refused = [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+            "224.0.0.1", "239.255.255.250", "255.255.255.255", "::1", "::", "fc00::1", "fd12::1", "fe80::1",
+            "ff02::1", "::ffff:127.0.0.1", "::ffff:192.168.1.1", "64:ff9b::127.0.0.1", "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::", "2002:c0a8:101::1", "::127.0.0.1", "::10.1.2.3",
+        ];
+        for ip in refused {
+            assert!(!is_public(ip.parse().unwrap()), "{} should be refused", ip);
+        }
+        let allowed = [
+            "93.184.215.14", "1.1.1.1", "2606:4700:4700::1111", "::ffff:93.184.215.14", "64:ff9b::1.1.1.1",
+            "2002:101:101::1",
+        ];
+        for ip in allowed {
+            assert!(is_public(ip.parse().unwrap()), "{} should be allowed", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_to_connect_to_private_addresses() {
+        let config = HttpClientConfig::default();
+        let client = HyperClient::new(&config).unwrap();
+        let request = Request::get("http://127.0.0.1:9/").body(Bytes::new()).unwrap();
+        let err = client.send(request).await.err().unwrap();
+        assert!(err.contains("refused to connect"), "{}", err);
+    }
+}
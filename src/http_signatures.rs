@@ -0,0 +1,385 @@
+use std::{
+    fs,
+    io::BufReader,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, Uri},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::{
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{self, RsaKeyPair},
+};
+use rustls::pki_types::PrivateKeyDer;
+
+
+
+// HTTP Signatures as the fediverse uses them, see
+// https://datatracker.ietf.org/doc/html/draft-cavage-http-signatures-12. Only rsa-sha256 is
+// supported, being the one every server understands
+
+const SIGNATURE: HeaderName = HeaderName::from_static("signature");
+const DIGEST: HeaderName = HeaderName::from_static("digest");
+
+// what our requests sign, `digest` only when there is a body
+const SIGNED_HEADERS: &[&str] = &["(request-target)", "host", "date"];
+
+// signed requests dated further than this from now are refused, allowing for clock skew
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60 * 60);
+
+// AlgorithmIdentifier of an RSA public key in DER, rsaEncryption with NULL parameters
+const RSA_ALGORITHM: &[u8] = &[
+    0x30, 0x0d, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01, 0x05, 0x00,
+];
+
+// the key requests are signed with, and how other servers find its public half
+pub(crate) struct SigningKey {
+    key_id: String,
+    key_pair: RsaKeyPair,
+    public_key_pem: String,
+}
+
+impl SigningKey {
+    pub(crate) fn load(path: &Path, key_id: String) -> Result<Self, String> {
+        let describe = |err: String| format!("failed to load private key {}: {}", path.display(), err);
+        let key = fs::File::open(path)
+            .map_err(|err| err.to_string())
+            .and_then(|file| rustls_pemfile::private_key(&mut BufReader::new(file)).map_err(|err| err.to_string()))
+            .map_err(describe)?
+            .ok_or_else(|| describe("no private key found".to_string()))?;
+        let key_pair = match &key {
+            PrivateKeyDer::Pkcs8(key) => RsaKeyPair::from_pkcs8(key.secret_pkcs8_der()),
+            PrivateKeyDer::Pkcs1(key) => RsaKeyPair::from_der(key.secret_pkcs1_der()),
+            _ => return Err(describe("not an RSA key".to_string())),
+        }
+        .map_err(|err| describe(err.to_string()))?;
+
+        let public_key = key_pair.public().as_ref();
+        let subject_public_key_info = der(0x30, &[RSA_ALGORITHM, &der(0x03, &[&[0], public_key].concat())].concat());
+        Ok(SigningKey {
+            key_id,
+            key_pair,
+            public_key_pem: pem("PUBLIC KEY", &subject_public_key_info),
+        })
+    }
+
+    pub(crate) fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    pub(crate) fn public_key_pem(&self) -> &str {
+        &self.public_key_pem
+    }
+
+    // adds `Date`, `Digest` when there is a body and `Signature` headers to a request
+    pub(crate) fn sign(&self, request: &mut Request<Bytes>) -> Result<(), String> {
+        let authority = request
+            .uri()
+            .authority()
+            .ok_or_else(|| format!("{} has no host", request.uri()))?
+            .as_str()
+            .to_string();
+        let date = httpdate::fmt_http_date(SystemTime::now());
+        let mut signed_headers = SIGNED_HEADERS.to_vec();
+        let headers = request.headers_mut();
+        // unwraps are fine as hosts, dates and base64 are plain ascii
+        headers.insert(header::HOST, HeaderValue::from_str(&authority).unwrap());
+        headers.insert(header::DATE, HeaderValue::from_str(&date).unwrap());
+        if !request.body().is_empty() {
+            let body_digest = format!("SHA-256={}", STANDARD.encode(digest(&SHA256, request.body())));
+            request.headers_mut().insert(DIGEST, HeaderValue::from_str(&body_digest).unwrap());
+            signed_headers.push("digest");
+        }
+
+        let signing_string = signing_string(request.method(), request.uri(), request.headers(), &signed_headers)?;
+        let mut signature = vec![0; self.key_pair.public().modulus_len()];
+        self.key_pair
+            .sign(&signature::RSA_PKCS1_SHA256, &SystemRandom::new(), signing_string.as_bytes(), &mut signature)
+            .map_err(|_| "failed to sign the request".to_string())?;
+        let value = format!(
+            "keyId=\"{}\",algorithm=\"rsa-sha256\",headers=\"{}\",signature=\"{}\"",
+            self.key_id,
+            signed_headers.join(" "),
+            STANDARD.encode(signature)
+        );
+        let value = HeaderValue::from_str(&value).map_err(|err| format!("bad signature header: {}", err))?;
+        request.headers_mut().insert(SIGNATURE, value);
+        Ok(())
+    }
+}
+
+// the parameters of a `Signature` header
+pub(crate) struct Signature {
+    pub(crate) key_id: String,
+    headers: Vec<String>,
+    signature: Vec<u8>,
+}
+
+impl Signature {
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Result<Self, &'static str> {
+        let value = headers.get(SIGNATURE).ok_or("unsigned")?.to_str().map_err(|_| "malformed")?;
+        let mut key_id = None;
+        let mut algorithm = None;
+        let mut signed_headers = None;
+        let mut signature = None;
+        for param in value.split(',') {
+            let Some((name, value)) = param.trim().split_once('=') else {
+                continue;
+            };
+            let value = value.trim_matches('"');
+            match name {
+                "keyId" => key_id = Some(value.to_string()),
+                "algorithm" => algorithm = Some(value.to_ascii_lowercase()),
+                "headers" => signed_headers = Some(value.split_whitespace().map(str::to_ascii_lowercase).collect()),
+                "signature" => signature = Some(STANDARD.decode(value).map_err(|_| "malformed")?),
+                _ => {}
+            }
+        }
+        // hs2019 is the newer name, which servers use for rsa-sha256 keys too
+        if algorithm.is_some_and(|algorithm| algorithm != "rsa-sha256" && algorithm != "hs2019") {
+            return Err("unsupported_algorithm");
+        }
+        Ok(Signature {
+            key_id: key_id.ok_or("malformed")?,
+            // `date` alone when not given, which is too little for us anyway
+            headers: signed_headers.unwrap_or_else(|| vec!["date".to_string()]),
+            signature: signature.ok_or("malformed")?,
+        })
+    }
+
+    // checks the signature is by the public key, covers the request target, host, date and any body,
+    // and is recent. The error is the reason for the metrics
+    pub(crate) fn verify(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        body: &[u8],
+        public_key_pem: &str,
+    ) -> Result<(), &'static str> {
+        let covers = |header: &str| self.headers.iter().any(|signed| signed == header);
+        let covers_body = body.is_empty() || covers("digest");
+        if !covers("(request-target)") || !covers("host") || !covers("date") || !covers_body {
+            return Err("too_little_signed");
+        }
+
+        let date = headers
+            .get(header::DATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+            .ok_or("malformed")?;
+        let now = SystemTime::now();
+        let skew = now.duration_since(date).or_else(|_| date.duration_since(now)).unwrap_or_default();
+        if skew > MAX_CLOCK_SKEW {
+            return Err("expired");
+        }
+
+        if !body.is_empty() {
+            let expected = STANDARD.encode(digest(&SHA256, body));
+            let is_match = headers
+                .get(DIGEST)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| {
+                    value.split(',').filter_map(|digest| digest.trim().split_once('=')).any(|(algorithm, value)| {
+                        algorithm.eq_ignore_ascii_case("sha-256") && value == expected
+                    })
+                });
+            if !is_match {
+                return Err("digest_mismatch");
+            }
+        }
+
+        let public_key = public_key_from_pem(public_key_pem).ok_or("bad_key")?;
+        let signed_headers: Vec<&str> = self.headers.iter().map(String::as_str).collect();
+        let signing_string = signing_string(method, uri, headers, &signed_headers).map_err(|_| "malformed")?;
+        signature::UnparsedPublicKey::new(&signature::RSA_PKCS1_2048_8192_SHA256, public_key)
+            .verify(signing_string.as_bytes(), &self.signature)
+            .map_err(|_| "invalid")
+    }
+}
+
+fn signing_string(method: &Method, uri: &Uri, headers: &HeaderMap, signed_headers: &[&str]) -> Result<String, String> {
+    let mut lines = Vec::new();
+    for name in signed_headers {
+        if *name == "(request-target)" {
+            let target = uri.path_and_query().map(|target| target.as_str()).unwrap_or("/");
+            lines.push(format!("(request-target): {} {}", method.as_str().to_ascii_lowercase(), target));
+            continue;
+        }
+        let mut values: Vec<&str> = headers
+            .get_all(*name)
+            .iter()
+            .map(|value| value.to_str().map(str::trim))
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("{} is not text", name))?;
+        // HTTP/2 requests carry the host in the uri rather than a header
+        if *name == "host" && values.is_empty() {
+            values.extend(uri.authority().map(|authority| authority.as_str()));
+        }
+        if values.is_empty() {
+            return Err(format!("{} is signed but missing", name));
+        }
+        lines.push(format!("{}: {}", name, values.join(", ")));
+    }
+    Ok(lines.join("\n"))
+}
+
+// the RSAPublicKey inside a PEM encoded SubjectPublicKeyInfo, which is how actors publish their keys
+fn public_key_from_pem(pem: &str) -> Option<Vec<u8>> {
+    let item = rustls_pemfile::read_one_from_slice(pem.as_bytes()).ok()?;
+    let Some((rustls_pemfile::Item::SubjectPublicKeyInfo(key), _)) = item else {
+        return None;
+    };
+    let (0x30, info, _) = read_der(key.as_ref())? else {
+        return None;
+    };
+    let (0x30, algorithm, rest) = read_der(info)? else {
+        return None;
+    };
+    // the parameters are sometimes left out, so only the object identifier is compared
+    if !algorithm.starts_with(&RSA_ALGORITHM[2..13]) {
+        return None;
+    }
+    let (0x03, bits, _) = read_der(rest)? else {
+        return None;
+    };
+    Some(bits.strip_prefix(&[0])?.to_vec())
+}
+
+// a DER value with a definite length
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    if contents.len() < 0x80 {
+        encoded.push(contents.len() as u8);
+    } else {
+        let length = contents.len().to_be_bytes();
+        let length = &length[length.iter().take_while(|byte| **byte == 0).count()..];
+        encoded.push(0x80 | length.len() as u8);
+        encoded.extend_from_slice(length);
+    }
+    encoded.extend_from_slice(contents);
+    encoded
+}
+
+// the tag, contents and whatever follows the first DER value in `data`
+fn read_der(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (length, rest) = if first < 0x80 {
+        (usize::from(first), rest)
+    } else {
+        let count = usize::from(first & 0x7f);
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let length = rest[..count].iter().fold(0, |length, byte| length << 8 | usize::from(*byte));
+        (length, &rest[count..])
+    };
+    (rest.len() >= length).then(|| (tag, &rest[..length], &rest[length..]))
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    // unwrap is fine as base64 is ascii
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::testdata;
+
+    const KEY_ID: &str = "https://remote.example/users/alice#main-key";
+
+    fn key(name: &str) -> SigningKey {
+        SigningKey::load(&testdata(name), KEY_ID.to_string()).unwrap()
+    }
+
+    fn signed(key: &SigningKey, body: &str) -> Request<Bytes> {
+        let mut request = Request::post("https://musings.example/inbox?page=1")
+            .body(Bytes::from(body.to_string()))
+            .unwrap();
+        key.sign(&mut request).unwrap();
+        request
+    }
+
+    fn verify(request: &Request<Bytes>, public_key_pem: &str) -> Result<(), &'static str> {
+        let signature = Signature::from_headers(request.headers())?;
+        signature.verify(request.method(), request.uri(), request.headers(), request.body(), public_key_pem)
+    }
+
+    #[test]
+    fn verifies_requests_signed_with_the_key() {
+        let key = key("rsa-1.pem");
+        let request = signed(&key, "{\"type\":\"Follow\"}");
+        assert_eq!(Signature::from_headers(request.headers()).unwrap().key_id, KEY_ID);
+        assert_eq!(verify(&request, key.public_key_pem()), Ok(()));
+
+        // the signature is only good for the request it was made for
+        let mut other = signed(&key, "");
+        *other.uri_mut() = "https://musings.example/inbox?page=2".parse().unwrap();
+        assert_eq!(verify(&other, key.public_key_pem()), Err("invalid"));
+    }
+
+    #[test]
+    fn refuses_signatures_by_other_keys() {
+        let request = signed(&key("rsa-1.pem"), "{}");
+        assert_eq!(verify(&request, key("rsa-2.pem").public_key_pem()), Err("invalid"));
+        assert_eq!(verify(&request, "not a key"), Err("bad_key"));
+    }
+
+    #[test]
+    fn refuses_requests_dated_too_far_from_now() {
+        let key = key("rsa-1.pem");
+        let now = SystemTime::now();
+        let hours = Duration::from_secs(2 * 60 * 60);
+        // a date near enough to now is still signed, so changing it breaks the signature
+        let dates = [(now - hours, "expired"), (now + hours, "expired"), (now - Duration::from_secs(300), "invalid")];
+        for (date, expected) in dates {
+            let mut request = signed(&key, "{}");
+            let date = HeaderValue::from_str(&httpdate::fmt_http_date(date)).unwrap();
+            request.headers_mut().insert(header::DATE, date);
+            assert_eq!(verify(&request, key.public_key_pem()), Err(expected));
+        }
+    }
+
+    #[test]
+    fn refuses_bodies_that_dont_match_the_digest() {
+        let key = key("rsa-1.pem");
+        let request = signed(&key, "{\"type\":\"Follow\"}");
+        let (parts, _) = request.into_parts();
+        let tampered = Request::from_parts(parts, Bytes::from("{\"type\":\"Delete\"}"));
+        assert_eq!(verify(&tampered, key.public_key_pem()), Err("digest_mismatch"));
+
+        let mut undigested = signed(&key, "{}");
+        undigested.headers_mut().remove(DIGEST);
+        assert_eq!(verify(&undigested, key.public_key_pem()), Err("digest_mismatch"));
+    }
+
+    #[test]
+    fn refuses_signatures_covering_too_little() {
+        let key = key("rsa-1.pem");
+        let mut request = signed(&key, "{}");
+        let value = request.headers()[SIGNATURE].to_str().unwrap().replace(" digest\"", "\"");
+        request.headers_mut().insert(SIGNATURE, HeaderValue::from_str(&value).unwrap());
+        assert_eq!(verify(&request, key.public_key_pem()), Err("too_little_signed"));
+
+        request.headers_mut().remove(SIGNATURE);
+        assert_eq!(verify(&request, key.public_key_pem()), Err("unsigned"));
+        let unsupported = format!("keyId=\"{}\",algorithm=\"hmac-sha256\",signature=\"AAAA\"", KEY_ID);
+        request.headers_mut().insert(SIGNATURE, HeaderValue::from_str(&unsupported).unwrap());
+        assert_eq!(verify(&request, key.public_key_pem()), Err("unsupported_algorithm"));
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    fmt,
+    fs,
+    future::Future,
+    io,
+    net::TcpListener,
+    os::unix::{fs::FileTypeExt, net::UnixListener},
+    path::Path,
+};
+
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use listenfd::ListenFd;
+
+use crate::config::ServerConfig;
+
+
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "tcp://{}", addr),
+                Err(_) => write!(f, "tcp://[unknown]"),
+            },
+            Listener::Unix(listener) => match listener.local_addr().ok().and_then(|addr| addr.as_pathname().map(Path::to_owned)) {
+                Some(path) => write!(f, "unix://{}", path.display()),
+                None => write!(f, "unix://[unnamed]"),
+            },
+        }
+    }
+}
+
+// every socket the app is served on. Sockets passed in through systemd socket activation replace the
+// configured address and port, the unix socket is served in addition to either
+pub(crate) fn bind(config: &ServerConfig) -> Result<Vec<Listener>, String> {
+    let mut listeners = systemd_listeners()?;
+
+    if listeners.is_empty() {
+        let address = format!("{}:{}", config.address, config.port);
+        let listener = TcpListener::bind(&address).map_err(|err| format!("failed to bind {}: {}", address, err))?;
+        listeners.push(Listener::Tcp(listener));
+    }
+
+    if let Some(path) = &config.unix_socket {
+        listeners.push(Listener::Unix(bind_unix(path)?));
+    }
+
+    // tokio takes over the sockets, which requires them to be non blocking
+    for listener in &listeners {
+        let result = match listener {
+            Listener::Tcp(listener) => listener.set_nonblocking(true),
+            Listener::Unix(listener) => listener.set_nonblocking(true),
+        };
+        result.map_err(|err| format!("failed to set up {}: {}", listener, err))?;
+    }
+
+    Ok(listeners)
+}
+
+// sockets inherited through `LISTEN_FDS`, in the order given in the systemd socket unit
+fn systemd_listeners() -> Result<Vec<Listener>, String> {
+    let mut fds = ListenFd::from_env();
+    let mut listeners = Vec::new();
+
+    for index in 0..fds.len() {
+        if let Ok(Some(listener)) = fds.take_tcp_listener(index) {
+            listeners.push(Listener::Tcp(listener));
+            continue;
+        }
+        match fds.take_unix_listener(index) {
+            Ok(Some(listener)) => listeners.push(Listener::Unix(listener)),
+            Ok(None) => {}
+            Err(_) => return Err(format!("socket activation fd {} is not a stream socket", index + 3)),
+        }
+    }
+
+    Ok(listeners)
+}
+
+fn bind_unix(path: &Path) -> Result<UnixListener, String> {
+    // a socket left behind by an unclean shutdown would fail the bind, anything else is not ours
+    // to remove
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            fs::remove_file(path).map_err(|err| format!("failed to remove stale socket {}: {}", path.display(), err))?;
+        }
+        Ok(_) => return Err(format!("{} exists and is not a socket", path.display())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(format!("failed to inspect {}: {}", path.display(), err)),
+    }
+
+    UnixListener::bind(path).map_err(|err| format!("failed to bind {}: {}", path.display(), err))
+}
+
+// axum only serves TCP listeners, so unix sockets are driven with hyper directly. There is no peer
+// address to add as `ConnectInfo`, client addresses have to come from forwarding headers
+pub(crate) async fn serve_unix(listener: UnixListener, app: Router, shutdown: impl Future<Output = ()>) -> io::Result<()> {
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    let graceful = GracefulShutdown::new();
+    let builder = auto::Builder::new(TokioExecutor::new());
+
+    tokio::pin!(shutdown);
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    tracing::error!("Failed to accept unix socket connection. Error: {:#?}", err);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let connection = builder
+            .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(app.clone()))
+            .into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                tracing::debug!("Unix socket connection closed. Error: {:#?}", err);
+            }
+        });
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+    Ok(())
+}
+
+// removes the unix socket this process created. Sockets passed in by systemd are left to systemd
+pub(crate) fn remove_unix_socket(config: &ServerConfig) {
+    if let Some(path) = &config.unix_socket {
+        let _ = fs::remove_file(path);
+    }
+}
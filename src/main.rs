@@ -1,143 +1,750 @@
+mod access_log;
+mod activitypub;
+mod admin_auth;
+mod analytics;
+mod charts;
+mod client_ip;
+mod comments;
+mod compression;
+mod config;
+mod content;
+mod cors;
+mod http_cache;
+mod http_client;
+mod http_signatures;
+#[cfg(feature = "http3")]
+mod http3;
+mod listeners;
+mod metrics;
+mod page_cache;
+mod proof_of_work;
+mod rankings;
+mod rate_limit;
+mod reactions;
+mod reload;
 mod services;
+mod spam;
+mod state;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod webmentions;
 
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{net::SocketAddr, path::{Path, PathBuf}, sync::Arc, time::Duration};
 
 use axum::{
-    http::{HeaderName, HeaderValue, Method, Request},
+    handler::HandlerWithoutStateExt,
+    http::{header, HeaderName, HeaderValue, Request, StatusCode},
+    middleware,
     response::Response,
-    routing::get,
+    routing::{get, post},
     Router,
 };
-use clap::{value_parser, Arg, ArgAction, Command};
-use tower::ServiceBuilder;
-use tower_governor::{governor::GovernorConfigBuilder, key_extractor::SmartIpKeyExtractor, GovernorLayer};
+use arc_swap::ArcSwap;
+use axum_server::tls_rustls::RustlsConfig;
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use tokio::task::JoinSet;
+use tower::{ServiceBuilder, ServiceExt};
 use tower_http::{
-    classify::ServerErrorsFailureClass, cors::CorsLayer, propagate_header::PropagateHeaderLayer, request_id::{MakeRequestUuid, SetRequestIdLayer}, trace::TraceLayer
+    classify::ServerErrorsFailureClass, propagate_header::PropagateHeaderLayer, request_id::{MakeRequestUuid, SetRequestIdLayer}, services::ServeDir, set_header::SetResponseHeaderLayer, trace::TraceLayer
 };
 use tracing::{info_span, Level, Span};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{fmt::format::JsonFields, layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
-use crate::services::{get_blog_post, handler_404, index, redirect};
+use crate::{
+    access_log::{capture_request, AccessLog},
+    activitypub::{actor, followers, inbox, outbox, webfinger},
+    admin_auth::{require_admin, AdminCredential},
+    analytics::{serve_stats, track_page_views},
+    client_ip::{resolve_client_ip, ClientIp},
+    comments::{edit_comment, get_comments, moderate_comment, serve_moderation, submit_comment},
+    http_cache::{http_cache, CachePolicies, Streamed},
+    config::{Config, LogFormat, LoggingConfig, TlsConfig},
+    listeners::Listener,
+    metrics::{serve_metrics, track_metrics},
+    page_cache::{purge_page_cache, BLOG_POST_ROUTE, POST_ROUTE},
+    proof_of_work::{issue_challenge, require_proof_of_work},
+    rankings::{api_popular_posts, api_trending_posts, popular_posts, trending_posts},
+    reactions::{api_reactions, get_reactions, react},
+    services::{get_blog_post, get_post, handler_404, index, redirect},
+    state::AppState,
+    telemetry::{FlattenedJson, RedactedHeaders},
+    webmentions::{get_webmentions, receive_webmention},
+};
 
 
 
 fn cmd() -> Command {
+    // settings given here override the config file and environment, see `config::Config`
     Command::new("amacerels-musings")
         .about("A musings store")
         .version(env!("CARGO_PKG_VERSION"))
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .help("TOML config file")
+                .env("AMACKEREL_CONFIG")
+                .required(false)
+                .global(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
         .arg(
             Arg::new("address")
                 .short('a')
                 .long("address")
-                .help("Server address")
-                .env("AMACKEREL_SERVER_ADDRESS")
-                .default_value("0.0.0.0"),
+                .help("Server address [default: 0.0.0.0]"),
         )
         .arg(
             Arg::new("port")
                 .short('p')
                 .long("port")
-                .help("Server port")
-                .env("AMACKEREL_SERVER_PORT")
-                .default_value("3000")
-                .value_parser(value_parser!(u32)),
+                .help("Server port [default: 3000]")
+                .value_parser(value_parser!(u16)),
+        )
+        .arg(
+            Arg::new("admin-port")
+                .long("admin-port")
+                .help("Serve admin endpoints such as /metrics on a separate port")
+                .value_parser(value_parser!(u16)),
+        )
+        .arg(
+            Arg::new("public-url")
+                .long("public-url")
+                .help("URL the site is reached at, e.g. https://musings.example.com [default: http://localhost:<port>]"),
+        )
+        .arg(
+            Arg::new("unix-socket")
+                .long("unix-socket")
+                .help("Also serve plain HTTP on this unix domain socket, e.g. behind nginx")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("trusted-proxies")
+                .long("trusted-proxies")
+                .help("Comma separated CIDR ranges of reverse proxies whose forwarding headers are trusted")
+                .value_delimiter(','),
+        )
+        .arg(
+            Arg::new("otlp-endpoint")
+                .long("otlp-endpoint")
+                .help("Export traces over OTLP/HTTP to this collector endpoint, e.g. http://localhost:4318/v1/traces"),
+        )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .help("Log output format [default: pretty]")
+                .value_parser(["pretty", "compact", "json"]),
+        )
+        .arg(
+            Arg::new("redact-headers")
+                .long("redact-headers")
+                .help("Comma separated headers whose values are masked in logs and traces")
+                .value_delimiter(',')
+                .value_parser(value_parser!(HeaderName)),
+        )
+        .arg(
+            Arg::new("access-log")
+                .long("access-log")
+                .help("Write an access log to this file, in addition to the tracing output")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("access-log-format")
+                .long("access-log-format")
+                .help("Access log format: common, combined or an Apache style format string [default: combined]"),
+        )
+        .arg(
+            Arg::new("access-log-max-size")
+                .long("access-log-max-size")
+                .help("Rotate the access log once it exceeds this many megabytes, as well as daily. 0 disables [default: 100]")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("access-log-max-files")
+                .long("access-log-max-files")
+                .help("Number of rotated access logs to keep. 0 keeps all [default: 7]")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("content-dir")
+                .long("content-dir")
+                .help("Directory of markdown posts [default: content]")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("static-dir")
+                .long("static-dir")
+                .help("Directory of static files served from the site root [default: static]")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("tls-cert")
+                .long("tls-cert")
+                .help("PEM certificate chain, serves HTTPS together with --tls-key")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("tls-key")
+                .long("tls-key")
+                .help("PEM private key for --tls-cert")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("http-redirect-port")
+                .long("http-redirect-port")
+                .help("With TLS enabled, also listen for plain HTTP on this port and redirect to HTTPS")
+                .value_parser(value_parser!(u16)),
+        )
+        .arg(
+            Arg::new("http3")
+                .long("http3")
+                .help("With TLS enabled, also serve HTTP/3 over QUIC on the same port (needs the http3 feature)")
+                .action(ArgAction::SetTrue),
         )
         .arg(
             Arg::new("verbosity")
                 .short('v')
-                .help("Set the log level")
+                .help("Set the log level, -v for debug and -vv for trace")
                 .required(false)
-                .env("AMACKEREL_SERVER_VERBOSITY")
                 .action(ArgAction::Count),
         )
+        .subcommand(
+            Command::new("config")
+                .about("Inspect the configuration")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("check")
+                        .about("Validate and print the effective configuration with secrets redacted"),
+                ),
+        )
+        .subcommand(
+            Command::new("assets")
+                .about("Prepare static assets")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("compress")
+                        .about("Write gzip, brotli and zstd variants of the static files, served instead of compressing on the fly"),
+                ),
+        )
 }
 
-fn handle_startup_commands() -> (String, String) {
+fn handle_startup_commands() -> (Config, ArgMatches) {
     let matches = cmd().get_matches();
 
-    // unwraps are fine as Clap has validated the inputs already
-    let address = matches.get_one::<String>("address").unwrap().to_owned();
-    let port = matches.get_one::<u32>("port").unwrap().to_string();
-    let log_level = match matches.get_one::<u8>("verbosity").unwrap() {
-        0 => Level::INFO,
-        1 => Level::DEBUG,
-        _ => Level::TRACE,
+    // logging isn't set up yet, so configuration errors go straight to stderr
+    let config = match Config::load(&matches) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid configuration:\n{}", err);
+            std::process::exit(1);
+        }
     };
+    for key in Config::unknown_env_keys() {
+        eprintln!("Ignoring environment variable for unknown setting {}.", key);
+    }
 
-    setup_tracing(log_level);
+    if let Some(("config", config_matches)) = matches.subcommand() {
+        if let Some(("check", _)) = config_matches.subcommand() {
+            // unwrap is fine as the config is made up of plain serializable types
+            print!("{}", toml::to_string_pretty(&config.redacted()).unwrap());
+            std::process::exit(0);
+        }
+    }
+    if let Some(("assets", assets_matches)) = matches.subcommand() {
+        if let Some(("compress", _)) = assets_matches.subcommand() {
+            let static_dir = &config.content.static_dir;
+            match compression::precompress(static_dir, &config.compression) {
+                Ok(compressed) => {
+                    println!("Precompressed {} files in {}", compressed, static_dir.display());
+                    std::process::exit(0);
+                }
+                Err(err) => {
+                    eprintln!("Failed to precompress assets:\n{}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    setup_tracing(&config.logging);
+    let redacted = config.redacted();
     tracing::info!("Server setup complete.");
-    tracing::info!("\tServer address: {}", address);
-    tracing::info!("\tServer port: {}", port);
-    tracing::info!("\tServer log level: {}", log_level.to_string());
-    
-    (address, port)
+    tracing::info!("\tServer address: {}", redacted.server.address);
+    tracing::info!("\tServer port: {}", redacted.server.port);
+    if let Some(admin_port) = redacted.server.admin_port {
+        tracing::info!("\tAdmin port: {}", admin_port);
+    }
+    if let Some(unix_socket) = &redacted.server.unix_socket {
+        tracing::info!("\tUnix socket: {}", unix_socket.display());
+    }
+    tracing::info!("\tTrusted proxies: {:?}", redacted.server.trusted_proxies);
+    tracing::info!("\tPublic URL: {}", redacted.server.public_url.as_deref().unwrap_or(&redacted.public_origin()));
+    tracing::info!("\tServer log level: {}", Level::from(redacted.logging.level));
+    tracing::info!("\tServer log format: {}", redacted.logging.format);
+    tracing::info!("\tRedacted headers: {:?}", redacted.logging.redact_headers);
+    if let Some(path) = &redacted.access_log.path {
+        tracing::info!("\tAccess log: {} ({})", path.display(), redacted.access_log.format);
+    }
+    if let Some(otlp_endpoint) = &redacted.logging.otlp_endpoint {
+        tracing::info!("\tOTLP endpoint: {}", otlp_endpoint);
+    }
+    tracing::info!("\tStatic files: {}", redacted.content.static_dir.display());
+    if redacted.compression.enabled {
+        tracing::info!("\tCompression: responses of {} bytes or more", redacted.compression.min_size);
+    }
+    if let (Some(cert), Some(key)) = (&redacted.tls.cert, &redacted.tls.key) {
+        tracing::info!("\tTLS certificate: {}", cert.display());
+        tracing::info!("\tTLS key: {}", key.display());
+    }
+    if redacted.tls.enabled() {
+        tracing::info!("\tHTTP/2: {}", redacted.tls.http2);
+        tracing::info!("\tHTTP/3: {}", redacted.tls.http3);
+    }
+    if let Some(http_redirect_port) = redacted.tls.http_redirect_port {
+        tracing::info!("\tHTTP redirect port: {}", http_redirect_port);
+    }
+
+    (config, matches)
 }
 
-fn setup_tracing(log_level: Level) {
+fn setup_tracing(config: &LoggingConfig) {
     // set both package and tower tracing to log level
     let tracing_env_var = format!(
         "{}={},tower_http=debug,axum::rejection=trace",
         env!("CARGO_PKG_NAME").replace("-", "_"),
-        log_level.as_str(),
+        Level::from(config.level).as_str(),
     );
 
+    // a broken exporter shouldn't stop the server, so report it once logging is available
+    let (otlp_layer, otlp_error) = match config.otlp_endpoint.as_ref().map(|endpoint| telemetry::otlp_layer(endpoint)) {
+        Some(Ok(layer)) => (Some(layer), None),
+        Some(Err(err)) => (None, Some(err)),
+        None => (None, None),
+    };
+
+    let fmt_layer = match config.format {
+        LogFormat::Compact => tracing_subscriber::fmt::layer().compact().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .fmt_fields(JsonFields::new())
+            .event_format(FlattenedJson)
+            .boxed(),
+        LogFormat::Pretty => tracing_subscriber::fmt::layer().pretty().boxed(),
+    };
+
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| tracing_env_var.into()),
         )
-        .with(tracing_subscriber::fmt::layer().pretty())
+        .with(fmt_layer)
+        .with(otlp_layer)
         .init();
+
+    if let Some(err) = otlp_error {
+        tracing::error!("Trace export disabled. {}", err);
+    }
 }
 
 fn main() {
-    let (address, port) = handle_startup_commands();
+    let (config, matches) = handle_startup_commands();
+
+    // bound before the runtime starts as taking over systemd sockets clears LISTEN_FDS from the
+    // environment, which isn't safe once other threads are running
+    let listeners = match listeners::bind(&config.server) {
+        Ok(listeners) => listeners,
+        Err(err) => {
+            tracing::error!("Failed to start listening. Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    run_app(config, matches, listeners);
 
-    run_app(address, port);
+    telemetry::shutdown();
 }
 
 #[tokio::main]
-async fn run_app(address: String, port: String) {
-
-    // rate limiting config. Set to 5 requests max with one request replenishing every 20 seconds.
-    // Uses a SmartIpKeyExtractor which tries to identify client IP through a number of options 
-    let governor_config = Arc::new(
-        GovernorConfigBuilder::default()
-            .per_second(20)
-            .burst_size(10)
-            .key_extractor(SmartIpKeyExtractor)
-            .use_headers()
-            .finish()
-            .unwrap()
-    );
+async fn run_app(config: Config, matches: ArgMatches, listeners: Vec<Listener>) {
+    let server = config.server.clone();
+    let address = server.address.clone();
+    let port = server.port;
+    let admin_port = server.admin_port;
+    let tls = config.tls.clone();
+
+    // register metrics up front so they are exported before the first request
+    metrics::metrics();
+
+    // the guard flushes any buffered access log lines once the server stops
+    let access_log = config
+        .access_log
+        .path
+        .as_ref()
+        .map(|path| AccessLog::new(path, &config.access_log));
+    let (access_log, _access_log_guard) = match access_log {
+        Some(Ok((access_log, guard))) => (Some(Arc::new(access_log)), Some(guard)),
+        Some(Err(err)) => {
+            tracing::error!("Failed to open the access log. Error: {}", err);
+            std::process::exit(1);
+        }
+        None => (None, None),
+    };
+
+    let state = match AppState::load(config) {
+        Ok(state) => Arc::new(state),
+        Err(err) => {
+            tracing::error!("Failed to load content, analytics, comments, reactions, webmentions or ActivityPub state. Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // admin endpoints are served on their own port when one is given, otherwise alongside the app.
+    // Without a credential they are only served on an admin port, and then only to this host
+    let has_admin_credential = server.admin_password.is_some();
+    if admin_port.is_none() && !has_admin_credential {
+        tracing::warn!(
+            "No server.admin_password set, /metrics, /stats, /moderation and /cache/purge are not served. \
+             Set one, or give them a server.admin_port."
+        );
+    }
+    let admin_listener = match admin_port {
+        Some(admin_port) => {
+            let admin_address = if has_admin_credential {
+                address.clone()
+            } else {
+                tracing::warn!("No server.admin_password set, the admin port only listens on 127.0.0.1.");
+                "127.0.0.1".to_string()
+            };
+            let admin_address = format!("{admin_address}:{admin_port}");
+            match tokio::net::TcpListener::bind(&admin_address).await {
+                Ok(listener) => Some(listener),
+                Err(err) => {
+                    tracing::error!("Failed to listen on {} for the admin endpoints. Error: {}", admin_address, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+    let build_router = move |state: Arc<AppState>| {
+        let app = app_router(state.clone(), access_log.clone());
+        match admin_port {
+            None if has_admin_credential => app.merge(admin_router(&state)),
+            _ => app,
+        }
+    };
+
+    // the routers are rebuilt from every reloaded state and swapped in atomically. A request is
+    // handled start to finish by whichever router was current when it arrived
+    let analytics = state.analytics.clone();
+    let router = Arc::new(ArcSwap::from_pointee(build_router(state.clone())));
+    let admin = admin_listener.map(|listener| (listener, Arc::new(ArcSwap::from_pointee(admin_router(&state)))));
+    let reloaded_router = router.clone();
+    let reloaded_admin = admin.as_ref().map(|(_, admin)| admin.clone());
+    state.announce();
+    tokio::spawn(reload::watch(matches, state, move |state| {
+        state.announce();
+        if let Some(admin) = &reloaded_admin {
+            admin.store(Arc::new(admin_router(&state)));
+        }
+        reloaded_router.store(Arc::new(build_router(state)));
+    }));
+    let app = swappable(router);
+
+    if let Some((admin_listener, admin)) = admin {
+        let admin = swappable(admin).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(admin_listener, admin).await {
+                tracing::error!("Serving the admin endpoints failed. Error: {}", err);
+                std::process::exit(1);
+            }
+        });
+    }
+
+    let (app, rustls_config) = match (&tls.cert, &tls.key) {
+        (Some(cert), Some(key)) => {
+            let (app, rustls_config) = start_tls(app, &tls, cert, key, &address, port, server.public_url.as_deref()).await;
+            (app, Some(rustls_config))
+        }
+        _ => (app, None),
+    };
+
+    // HTTPS listeners stop through the handle, the others through their own shutdown future
+    let handle = axum_server::Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        shutdown_handle.graceful_shutdown(None);
+    });
+
+    // every listener serves the same app. With TLS enabled TCP listeners serve HTTPS, unix sockets
+    // always serve plain HTTP as they are only reachable from the same host
+    let mut servers = JoinSet::new();
+    for listener in listeners {
+        tracing::info!("Listening on {}", listener);
+        match listener {
+            Listener::Tcp(listener) => match &rustls_config {
+                Some(rustls_config) => {
+                    servers.spawn(
+                        axum_server::from_tcp_rustls(listener, rustls_config.clone())
+                            .handle(handle.clone())
+                            .serve(app.clone().into_make_service_with_connect_info::<SocketAddr>()),
+                    );
+                }
+                None => {
+                    let app = app.clone();
+                    servers.spawn(async move {
+                        axum::serve(
+                            tokio::net::TcpListener::from_std(listener)?,
+                            app.into_make_service_with_connect_info::<SocketAddr>(),
+                        )
+                        .with_graceful_shutdown(shutdown_signal())
+                        .await
+                    });
+                }
+            },
+            Listener::Unix(listener) => {
+                servers.spawn(listeners::serve_unix(listener, app.clone(), shutdown_signal()));
+            }
+        }
+    }
+    while let Some(result) = servers.join_next().await {
+        if let Ok(Err(err)) = result {
+            tracing::error!("Listener stopped. Error: {:#?}", err);
+        }
+    }
+
+    listeners::remove_unix_socket(&server);
+    analytics.flush();
+}
+
+// loads the certificate, starts watching it for changes along with the optional HTTP/3 and redirect
+// listeners. Returns the app to serve over HTTPS
+async fn start_tls(
+    app: Router,
+    tls: &TlsConfig,
+    cert: &Path,
+    key: &Path,
+    address: &str,
+    port: u16,
+    public_url: Option<&str>,
+) -> (Router, RustlsConfig) {
+    let rustls_config = match tls::load(cert, key, tls.http2) {
+        Ok(rustls_config) => rustls_config,
+        Err(err) => {
+            tracing::error!("Failed to start HTTPS. Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // HTTP/3 is served from a QUIC endpoint on the same port number, browsers learn about it from
+    // the `Alt-Svc` header on responses over TCP and switch for later requests
+    #[cfg(feature = "http3")]
+    let (app, http3_endpoint) = match tls.http3 {
+        true => {
+            let endpoint = match http3::bind(&format!("{address}:{port}"), cert, key) {
+                Ok(endpoint) => endpoint,
+                Err(err) => {
+                    tracing::error!("Failed to start HTTP/3. Error: {}", err);
+                    std::process::exit(1);
+                }
+            };
+            // unwrap is fine as the value is plain ascii
+            let alt_svc = HeaderValue::from_str(&format!("h3=\":{port}\"; ma=86400")).unwrap();
+            let app = app.layer(SetResponseHeaderLayer::overriding(header::ALT_SVC, alt_svc));
+            tokio::spawn(http3::serve(endpoint.clone(), app.clone()));
+            let shutdown_endpoint = endpoint.clone();
+            tokio::spawn(async move {
+                shutdown_signal().await;
+                shutdown_endpoint.close(0u32.into(), b"shutdown");
+            });
+            (app, Some(endpoint))
+        }
+        false => (app, None),
+    };
+
+    let reloaded_rustls_config = rustls_config.clone();
+    let http2 = tls.http2;
+    tokio::spawn(tls::watch_certificates(cert.to_owned(), key.to_owned(), move |cert, key| {
+        let server_config = tls::server_config(cert, key, tls::alpn_protocols(http2))?;
+        reloaded_rustls_config.reload_from_config(Arc::new(server_config));
+        #[cfg(feature = "http3")]
+        if let Some(endpoint) = &http3_endpoint {
+            endpoint.set_server_config(Some(http3::server_config(cert, key)?));
+        }
+        Ok(())
+    }));
+
+    if let Some(http_redirect_port) = tls.http_redirect_port {
+        let redirect_address = format!("{address}:{http_redirect_port}");
+        let redirect_listener = match tokio::net::TcpListener::bind(&redirect_address).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                tracing::error!("Failed to listen on {} to redirect to HTTPS. Error: {}", redirect_address, err);
+                std::process::exit(1);
+            }
+        };
+        let redirect = tls::redirect_router(port, public_url);
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(redirect_listener, redirect).await {
+                tracing::error!("Redirecting to HTTPS failed. Error: {}", err);
+                std::process::exit(1);
+            }
+        });
+    }
+
+    (app, rustls_config)
+}
 
+// serves each request with whichever router is current when it arrives, see `run_app`
+fn swappable(router: Arc<ArcSwap<Router>>) -> Router {
+    Router::new().fallback(move |request: axum::extract::Request| {
+        let router = router.load_full();
+        async move { Router::clone(&router).oneshot(request).await }
+    })
+}
+
+// admin endpoints are kept out of the app middleware stack so scrapes are neither traced nor
+// counted, only rate limited
+fn admin_router(state: &Arc<AppState>) -> Router {
+    // every admin endpoint is behind the admin credential, see `admin_auth`
+    Router::new()
+        .route("/metrics", get(serve_metrics))
+        .route("/stats", get(serve_stats))
+        .route("/cache/purge", post(purge_page_cache))
+        .route("/moderation", get(serve_moderation))
+        .route("/moderation/:id/:action", post(moderate_comment))
+        .with_state(state.clone())
+        .layer(
+            ServiceBuilder::new()
+                .layer(middleware::from_fn_with_state(
+                    Arc::new(state.config.server.trusted_proxy_networks()),
+                    resolve_client_ip,
+                ))
+                .layer(middleware::from_fn_with_state(state.rate_limiters.admin.clone(), rate_limit::rate_limit))
+                .layer(middleware::from_fn_with_state(
+                    Arc::new(AdminCredential::new(&state.config.server)),
+                    require_admin,
+                )),
+        )
+}
+
+
+fn app_router(state: Arc<AppState>, access_log: Option<Arc<AccessLog>>) -> Router {
+    // every group of routes is rate limited per client IP under its own policy, see
+    // `config::RateLimitConfig`. Requests from the allowlist are never limited
+    let limiters = &state.rate_limiters;
+
+    // static files are served from the site root, with a precompressed variant when the client
+    // accepts one. Anything else is a 404
+    // unwrap is fine as the policy is checked by `Config::validate`
+    let static_policy = HeaderValue::from_str(&state.config.cache_control.static_files).unwrap();
+    let static_files = ServiceBuilder::new()
+        // streamed from disk, `http_cache` leaves them be
+        .map_response(|mut response: Response<_>| {
+            response.extensions_mut().insert(Streamed);
+            response
+        })
+        // caches must keep the variants apart, `ServeDir` doesn't say they differ
+        .layer(SetResponseHeaderLayer::appending(header::VARY, HeaderValue::from_static("accept-encoding")))
+        // `ServeDir` answers `If-Modified-Since` itself, 404s are left uncached
+        .layer(SetResponseHeaderLayer::if_not_present(header::CACHE_CONTROL, move |response: &Response<_>| {
+            let status = response.status();
+            (status.is_success() || status == StatusCode::NOT_MODIFIED).then(|| static_policy.clone())
+        }))
+        .service(
+            ServeDir::new(&state.config.content.static_dir)
+                .precompressed_zstd()
+                .precompressed_br()
+                .precompressed_gzip()
+                .fallback(handler_404.into_service()),
+        );
+
+    // full pages, unknown paths are limited as pages too so scanning for them isn't free
+    let pages = Router::new()
+        .route("/", get(index))
+        .route(&format!("{}/:id", POST_ROUTE), get(get_post))
+        .fallback_service(static_files)
+        .layer(middleware::from_fn_with_state(limiters.pages.clone(), rate_limit::rate_limit));
+    // posting comments, limited much more tightly than reading them
+    let comment_writes = Router::new()
+        .route("/comments", post(submit_comment))
+        .route("/comments/edit", post(edit_comment))
+        .layer(
+            ServiceBuilder::new()
+                .layer(middleware::from_fn_with_state(limiters.comments.clone(), rate_limit::rate_limit))
+                .layer(middleware::from_fn_with_state(state.clone(), require_proof_of_work)),
+        );
+    // reacting costs a proof of work but is otherwise limited as a fragment
+    let reaction_writes = Router::new()
+        .route("/reactions", post(react))
+        .layer(middleware::from_fn_with_state(state.clone(), require_proof_of_work));
+    // htmx fragments
+    let fragments = Router::new()
+        .route("/redirect", get(redirect))
+        .route(BLOG_POST_ROUTE, get(get_blog_post))
+        .route("/popular", get(popular_posts))
+        .route("/trending", get(trending_posts))
+        .route("/comments", get(get_comments))
+        .merge(comment_writes)
+        .route("/reactions", get(get_reactions))
+        .merge(reaction_writes)
+        .route("/webmentions", get(get_webmentions))
+        .layer(middleware::from_fn_with_state(limiters.fragments.clone(), rate_limit::rate_limit));
+    // JSON endpoints
+    let api = Router::new()
+        .route("/api/posts/popular", get(api_popular_posts))
+        .route("/api/posts/trending", get(api_trending_posts))
+        .route("/api/posts/:id/reactions", get(api_reactions))
+        .route("/api/pow/challenge", get(issue_challenge))
+        .route("/webmention", post(receive_webmention))
+        .route("/.well-known/webfinger", get(webfinger))
+        .route("/actor", get(actor))
+        .route("/outbox", get(outbox))
+        .route("/followers", get(followers))
+        .route("/inbox", post(inbox))
+        .layer(middleware::from_fn_with_state(limiters.api.clone(), rate_limit::rate_limit));
+
+    let trusted_proxies = Arc::new(state.config.server.trusted_proxy_networks());
+    // the reactions fragment shows what the visitor chose and the comments one carries the comment form
+    let cache_policies = CachePolicies::new(&state.config.cache_control, &["/comments", "/reactions"]);
+
+    // shared between the per request span callbacks
+    let redact_headers = Arc::new(state.config.logging.redact_header_names());
+    let redact_response_headers = redact_headers.clone();
 
     // TODO: security headers
     // TODO: global 404 handler with Span
     // TODO: error handling and on error request.
-    let app = Router::new()
-        .route("/", get(index))
-        .route("/redirect", get(redirect))
-        .route("/blog-post", get(get_blog_post))
+    Router::new()
+        .merge(pages)
+        .merge(fragments)
+        .merge(api)
         .layer(
         // add middlewear, this is executed from top to bottom
         ServiceBuilder::new()
             // set `x-request-id` header on all requests and propogate to response
             .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
             .layer(PropagateHeaderLayer::new(HeaderName::from_static("x-request-id")))
+            // work out the client address once, for the span, access log and rate limiting
+            .layer(middleware::from_fn_with_state(trusted_proxies, resolve_client_ip))
             // set tracing details
             .layer(
                 TraceLayer::new_for_http()
-                    .make_span_with(|request: &Request<_>| {
+                    .make_span_with(move |request: &Request<_>| {
                         let request_id = request
                             .headers()
                             .get("x-request-id")
                             .map(|v| v.to_str().unwrap_or_default())
                             .unwrap_or_default();
 
-                        info_span!(
+                        let span = info_span!(
                             "http_request",
+                            otel.kind = "server",
                             request_id = request_id,
+                            client_ip = tracing::field::Empty,
                             status_code = tracing::field::Empty,
                             latency_ms = tracing::field::Empty,
                             method = ?request.method(),
@@ -145,16 +752,39 @@ async fn run_app(address: String, port: String) {
                             version = ?request.version(),
                             response_headers = tracing::field::Empty,
                             request_headers = tracing::field::Empty,
-                        )
+                        );
+                        if let Some(ClientIp(Some(client_ip))) = request.extensions().get::<ClientIp>() {
+                            span.record("client_ip", tracing::field::display(client_ip));
+                        }
+                        span.record(
+                            "request_headers",
+                            tracing::field::debug(RedactedHeaders {
+                                headers: request.headers(),
+                                redact: &redact_headers,
+                            }),
+                        );
+                        // continue the caller's trace when a W3C `traceparent` header is present
+                        span.set_parent(telemetry::extract_trace_context(request.headers()));
+                        span
                     })
-                    .on_request(|request: &Request<_>, span: &Span| {
+                    .on_request(|_request: &Request<_>, _span: &Span| {
                         tracing::debug!("Entering span...");
                     })
-                    .on_response(|response: &Response, latency: Duration, span: &Span| {
+                    .on_response(move |response: &Response, latency: Duration, span: &Span| {
                         let status_code = response.status();
                         let latency_ms = latency.as_millis();
                         span.record("status_code", status_code.as_str());
                         span.record("latency_ms", latency_ms);
+                        span.record(
+                            "response_headers",
+                            tracing::field::debug(RedactedHeaders {
+                                headers: response.headers(),
+                                redact: &redact_response_headers,
+                            }),
+                        );
+                        if let Some(access_log) = &access_log {
+                            access_log.record(response, latency);
+                        }
 
                         tracing::debug!("...span ended")
                     })
@@ -174,25 +804,38 @@ async fn run_app(address: String, port: String) {
                     },
                 ),
             )
-            // implement rate limiting based on IP addr (not ideal but better than nothing) 
-            .layer(GovernorLayer { config: governor_config })
-            // implement CORS handling
-            .layer(
-                // pay attention that for some request types like posting content-type: application/json
-                // it is required to add ".allow_headers([http::header::CONTENT_TYPE])"
-                // or see this issue https://github.com/tokio-rs/axum/issues/849
-                // TODO: SET ACTUAL HOST BASED ON CLI ARGS
-                CorsLayer::new()
-                    .allow_origin("http://localhost:3000".parse::<HeaderValue>().unwrap())
-                    .allow_methods([Method::GET]),
-            ),
+            // hand the request details needed for the access log through to `on_response`
+            .layer(middleware::from_fn(capture_request))
+            // record request duration metrics, including requests rejected by the rate limiter
+            .layer(middleware::from_fn(track_metrics))
+            // first party analytics, see `analytics::Analytics`
+            .layer(middleware::from_fn_with_state(state.clone(), track_page_views))
+            // `Cache-Control`, ETags and 304s, see `config::CacheControlConfig`
+            .layer(middleware::from_fn_with_state(cache_policies, http_cache))
+            // compress responses, see `config::CompressionConfig`. Inside the trace layer so the
+            // logged response headers are the ones the client received
+            .layer(compression::layer(&state.config.compression))
+            // implement CORS handling, see `config::CorsConfig`. Requests with a JSON body need
+            // `content-type` in `cors.allow_headers`, see https://github.com/tokio-rs/axum/issues/849
+            .layer(cors::layer(&state.config)),
     )
-    // generic 404 fallback
-    .fallback(handler_404);
-
-    let listener = tokio::net::TcpListener::bind(format!("{address}:{port}"))
-        .await
-        .unwrap();
-    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    .with_state(state)
 }
 
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.unwrap();
+    };
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("Shutdown signal received. Stopping server...");
+}
@@ -0,0 +1,156 @@
+use std::{sync::OnceLock, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    linear_buckets, register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+
+
+// all metrics are registered against the prometheus default registry which, with the `process`
+// feature enabled, also exposes the process stats (cpu, memory, open fds etc.) on linux
+pub(crate) struct Metrics {
+    pub(crate) request_duration: HistogramVec,
+    pub(crate) rate_limited: IntCounterVec,
+    pub(crate) posts_served: IntCounter,
+    pub(crate) page_cache_requests: IntCounterVec,
+    pub(crate) page_cache_pages: IntGauge,
+    pub(crate) comments: IntCounterVec,
+    pub(crate) comments_blocked: IntCounterVec,
+    pub(crate) proof_of_work_difficulty: Histogram,
+    pub(crate) proof_of_work_rejected: IntCounterVec,
+    pub(crate) reactions: IntCounterVec,
+    pub(crate) webmentions: IntCounterVec,
+    pub(crate) activitypub_followers: IntGauge,
+    pub(crate) activitypub_deliveries: IntCounterVec,
+    pub(crate) activitypub_inbox: IntCounterVec,
+    pub(crate) activitypub_inbox_rejected: IntCounterVec,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub(crate) fn metrics() -> &'static Metrics {
+    // unwraps are fine as registration only fails on duplicate names, which is a programming error
+    METRICS.get_or_init(|| Metrics {
+        request_duration: register_histogram_vec!(
+            "http_request_duration_seconds",
+            "HTTP request latency by matched route, method and status code",
+            &["route", "method", "status"]
+        )
+        .unwrap(),
+        rate_limited: register_int_counter_vec!(
+            "http_requests_rate_limited_total",
+            "Requests rejected by the rate limiter, by route group",
+            &["group"]
+        )
+        .unwrap(),
+        posts_served: register_int_counter!(
+            "blog_posts_served_total",
+            "Blog posts rendered and returned to a client"
+        )
+        .unwrap(),
+        page_cache_requests: register_int_counter_vec!(
+            "page_cache_requests_total",
+            "Page cache lookups, by result (hit or miss)",
+            &["result"]
+        )
+        .unwrap(),
+        page_cache_pages: register_int_gauge!("page_cache_pages", "Rendered pages held in the page cache").unwrap(),
+        comments: register_int_counter_vec!(
+            "comments_total",
+            "Comments submitted, edited and moderated, by event",
+            &["event"]
+        )
+        .unwrap(),
+        comments_blocked: register_int_counter_vec!(
+            "comments_blocked_total",
+            "Comments refused or filed as spam by the spam defences, by reason",
+            &["reason"]
+        )
+        .unwrap(),
+        proof_of_work_difficulty: register_histogram!(
+            "proof_of_work_difficulty",
+            "Difficulty of the proof of work challenges issued, in leading zero bits",
+            linear_buckets(1.0, 1.0, 28).unwrap()
+        )
+        .unwrap(),
+        proof_of_work_rejected: register_int_counter_vec!(
+            "proof_of_work_rejected_total",
+            "Writes refused for a missing or bad proof of work, by reason",
+            &["reason"]
+        )
+        .unwrap(),
+        reactions: register_int_counter_vec!(
+            "reactions_total",
+            "Reactions added or taken back, by event",
+            &["event"]
+        )
+        .unwrap(),
+        webmentions: register_int_counter_vec!(
+            "webmentions_total",
+            "Webmentions received, verified and sent, by event",
+            &["event"]
+        )
+        .unwrap(),
+        activitypub_followers: register_int_gauge!("activitypub_followers", "Fediverse accounts following the blog")
+            .unwrap(),
+        activitypub_deliveries: register_int_counter_vec!(
+            "activitypub_deliveries_total",
+            "Activities delivered to followers' inboxes, by result (delivered, retried or failed)",
+            &["result"]
+        )
+        .unwrap(),
+        activitypub_inbox: register_int_counter_vec!(
+            "activitypub_inbox_total",
+            "Activities taken by the inbox, by activity (follow, undo or ignored)",
+            &["activity"]
+        )
+        .unwrap(),
+        activitypub_inbox_rejected: register_int_counter_vec!(
+            "activitypub_inbox_rejected_total",
+            "Inbox activities refused for a missing or bad signature, by reason",
+            &["reason"]
+        )
+        .unwrap(),
+    })
+}
+
+
+pub(crate) async fn track_metrics(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    // routes are labelled by their matched pattern rather than the raw uri to keep cardinality bounded
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = request.method().to_string();
+
+    let response = next.run(request).await;
+
+    metrics()
+        .request_duration
+        .with_label_values(&[&route, &method, response.status().as_str()])
+        .observe(start.elapsed().as_secs_f64());
+
+    response
+}
+
+
+pub(crate) async fn serve_metrics() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("Failed to encode metrics. Error: {:#?}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    ([(header::CONTENT_TYPE, encoder.format_type().to_owned())], buffer).into_response()
+}
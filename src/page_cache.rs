@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{header, HeaderMap, HeaderValue},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::{config::Config, content::Content, metrics::metrics, state::AppState};
+
+
+
+pub(crate) const BLOG_POST_ROUTE: &str = "/blog-post";
+
+// a single post on a page of its own, the link to share and for other sites to mention
+pub(crate) const POST_ROUTE: &str = "/posts";
+
+const HX_REQUEST: &str = "hx-request";
+
+// what a page is rendered from. htmx requests are cached apart from full page loads as they may be
+// rendered differently
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) struct PageKey {
+    route: &'static str,
+    // the `id` parameter of post routes
+    id: Option<String>,
+    htmx: bool,
+}
+
+impl PageKey {
+    pub(crate) fn new(route: &'static str, id: Option<String>, headers: &HeaderMap) -> Self {
+        let htmx = headers.get(HX_REQUEST).is_some_and(|value| value == "true");
+        PageKey { route, id, htmx }
+    }
+}
+
+// rendered html along with the headers that go with it
+pub(crate) struct Page {
+    body: Bytes,
+    last_modified: Option<HeaderValue>,
+    // the post shown on the page, if any, to work out which pages a content change affects
+    post: Option<String>,
+}
+
+impl Page {
+    pub(crate) fn new(body: String) -> Self {
+        Page {
+            body: body.into(),
+            last_modified: None,
+            post: None,
+        }
+    }
+
+    pub(crate) fn with_post(mut self, post: String, last_modified: HeaderValue) -> Self {
+        self.post = Some(post);
+        self.last_modified = Some(last_modified);
+        self
+    }
+
+    pub(crate) fn to_response(&self) -> Response {
+        let mut response = Html(self.body.clone()).into_response();
+        if let Some(last_modified) = &self.last_modified {
+            response.headers_mut().insert(header::LAST_MODIFIED, last_modified.clone());
+        }
+        response
+    }
+}
+
+// rendered pages, shared by every state so it outlives reloads. Each reload drops just the pages it
+// changes, see `invalidate`
+pub(crate) struct PageCache {
+    pages: Mutex<Pages>,
+}
+
+struct Pages {
+    // bumped on every reload, so a render from the state before one isn't cached after it
+    revision: u64,
+    entries: HashMap<PageKey, Arc<Page>>,
+}
+
+impl PageCache {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(PageCache {
+            pages: Mutex::new(Pages {
+                revision: 0,
+                entries: HashMap::new(),
+            }),
+        })
+    }
+
+    // the cached page for the key, rendering and caching it on a miss. Only successful renders should
+    // get here, so that unknown ids can't fill the cache
+    pub(crate) fn get_or_render<F>(&self, state: &AppState, key: PageKey, render: F) -> Arc<Page>
+    where
+        F: FnOnce() -> Page,
+    {
+        if !state.config.page_cache.enabled {
+            return Arc::new(render());
+        }
+
+        if let Some(page) = self.pages.lock().unwrap().entries.get(&key) {
+            metrics().page_cache_requests.with_label_values(&["hit"]).inc();
+            return page.clone();
+        }
+        metrics().page_cache_requests.with_label_values(&["miss"]).inc();
+
+        // rendered without holding the lock, two requests racing for the same page both render it
+        let page = Arc::new(render());
+        let mut pages = self.pages.lock().unwrap();
+        if pages.revision == state.revision {
+            pages.entries.insert(key, page.clone());
+            metrics().page_cache_pages.set(pages.entries.len() as i64);
+        }
+        page
+    }
+
+    // drops the pages that would render differently from the reloaded config and content, returning
+    // the revision for the new state. A config change may show on any page, e.g. through the base
+    // template, so clears everything
+    pub(crate) fn invalidate(&self, current: &AppState, config: &Config, content: &Content) -> u64 {
+        let mut pages = self.pages.lock().unwrap();
+        pages.revision += 1;
+
+        if *config != current.config {
+            pages.entries.clear();
+        } else {
+            let changed = content.changed_since(&current.content);
+            pages.entries.retain(|key, page| match key.route {
+                // shows the post following `id`, which changes when a post is added or removed
+                // around it as well as when the post itself is edited
+                BLOG_POST_ROUTE => {
+                    let post = content.next(key.id.as_deref()).map(|post| post.id.clone());
+                    post == page.post && !post.is_some_and(|post| changed.contains(&post))
+                }
+                POST_ROUTE => !key.id.as_ref().is_some_and(|id| changed.contains(id)),
+                _ => true,
+            });
+        }
+
+        metrics().page_cache_pages.set(pages.entries.len() as i64);
+        tracing::debug!("Page cache revision {}, {} pages kept.", pages.revision, pages.entries.len());
+        pages.revision
+    }
+
+    fn purge(&self) -> usize {
+        let mut pages = self.pages.lock().unwrap();
+        let purged = pages.entries.len();
+        pages.entries.clear();
+        metrics().page_cache_pages.set(0);
+        purged
+    }
+}
+
+// admin endpoint dropping every cached page
+pub(crate) async fn purge_page_cache(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let purged = state.page_cache.purge();
+    tracing::info!("Purged {} pages from the page cache.", purged);
+    Json(json!({ "purged": purged }))
+}
@@ -0,0 +1,294 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    digest::{digest, SHA256},
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{client_ip::ClientIp, config::ProofOfWorkConfig, metrics::metrics, state::AppState};
+
+
+
+// `<challenge>:<counter>`, sent by `static/pow.js` with every protected request
+const X_PROOF_OF_WORK: HeaderName = HeaderName::from_static("x-proof-of-work");
+
+// challenges are only asked for the paths of the protected routes, anything much longer is junk
+const MAX_PATH_LENGTH: usize = 100;
+
+// spent challenges, and the load of clients no longer asking for any, are swept once there are this
+// many rather than on every request
+const SWEEP_SIZE: usize = 1024;
+
+// hashcash style proof of work for anonymous writes. The server hands out a signed challenge bound to
+// the path it is for and the client's address, and the client must find a counter whose SHA-256 with
+// the challenge starts with `difficulty` zero bits. A challenge can be used once, until it expires.
+// Shared by every state so spent challenges and the load survive reloads
+pub(crate) struct ProofOfWork {
+    // only lives as long as the process, challenges from before a restart are refused
+    key: hmac::Key,
+    // signature of each spent challenge -> when it expires
+    spent: Mutex<HashMap<String, u64>>,
+    // by client address, so one client asking for lots of challenges only makes them harder for itself
+    load: Mutex<HashMap<Option<IpAddr>, Load>>,
+    // of every client together, so many addresses asking a few each still make them harder
+    total_load: Mutex<Load>,
+}
+
+// challenges issued to a client in the current and previous minute
+#[derive(Default)]
+struct Load {
+    minute: u64,
+    this_minute: u64,
+    last_minute: u64,
+}
+
+impl Load {
+    // counts a challenge, returning the busier of this and the last minute so far
+    fn record(&mut self, now: u64) -> u64 {
+        let minute = now / 60;
+        if minute != self.minute {
+            self.last_minute = if minute == self.minute + 1 { self.this_minute } else { 0 };
+            self.this_minute = 0;
+            self.minute = minute;
+        }
+        self.this_minute += 1;
+        self.this_minute.max(self.last_minute)
+    }
+}
+
+impl ProofOfWork {
+    pub(crate) fn new() -> Arc<Self> {
+        // unwrap is fine as the system random source only fails when the OS has none
+        Arc::new(ProofOfWork {
+            key: hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()).unwrap(),
+            spent: Mutex::new(HashMap::new()),
+            load: Mutex::new(HashMap::new()),
+            total_load: Mutex::new(Load::default()),
+        })
+    }
+
+    // `<expires>.<difficulty>.<salt>.<signature>`. Each doubling of the challenges issued to the client
+    // a minute over `load_threshold`, or to everyone over `global_load_threshold`, adds a bit to the
+    // difficulty, doubling the work
+    fn issue(&self, config: &ProofOfWorkConfig, path: &str, client_ip: Option<IpAddr>) -> (String, u32) {
+        let now = unix_now();
+        let load = {
+            let mut loads = self.load.lock().unwrap();
+            if loads.len() >= SWEEP_SIZE {
+                loads.retain(|_, load| load.minute + 1 >= now / 60);
+            }
+            loads.entry(client_ip).or_default().record(now)
+        };
+        let total_load = self.total_load.lock().unwrap().record(now);
+        let raised = doublings(load, config.load_threshold).max(doublings(total_load, config.global_load_threshold));
+        let difficulty = config.difficulty.saturating_add(raised).min(config.max_difficulty);
+        metrics().proof_of_work_difficulty.observe(f64::from(difficulty));
+
+        let mut salt = [0; 12];
+        // unwrap is fine as the system random source only fails when the OS has none
+        SystemRandom::new().fill(&mut salt).unwrap();
+        let claims = format!("{}.{}.{}", now + config.expiry_secs, difficulty, URL_SAFE_NO_PAD.encode(salt));
+        let signature = hmac::sign(&self.key, bound(&claims, path, client_ip).as_bytes());
+        (format!("{}.{}", claims, URL_SAFE_NO_PAD.encode(signature)), difficulty)
+    }
+
+    // checks a solution is for a challenge issued for this path and client that is unspent, unexpired
+    // and solved to its difficulty, spending it. The error is the reason for the metrics
+    fn verify(&self, solution: Option<&str>, path: &str, client_ip: Option<IpAddr>) -> Result<(), &'static str> {
+        let solution = solution.ok_or("missing")?;
+        let (challenge, _counter) = solution.rsplit_once(':').ok_or("malformed")?;
+        let (claims, signature) = challenge.rsplit_once('.').ok_or("malformed")?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| "malformed")?;
+        hmac::verify(&self.key, bound(claims, path, client_ip).as_bytes(), &signature).map_err(|_| "invalid")?;
+
+        let mut claims = claims.split('.');
+        let expires: u64 = claims.next().and_then(|expires| expires.parse().ok()).ok_or("malformed")?;
+        let difficulty: u32 = claims.next().and_then(|difficulty| difficulty.parse().ok()).ok_or("malformed")?;
+        let now = unix_now();
+        if now > expires {
+            return Err("expired");
+        }
+        if leading_zero_bits(digest(&SHA256, solution.as_bytes()).as_ref()) < difficulty {
+            return Err("unsolved");
+        }
+
+        let mut spent = self.spent.lock().unwrap();
+        if spent.len() >= SWEEP_SIZE {
+            spent.retain(|_, expires| *expires >= now);
+        }
+        if spent.insert(URL_SAFE_NO_PAD.encode(&signature), expires).is_some() {
+            return Err("spent");
+        }
+        Ok(())
+    }
+}
+
+// one for `load` going over `threshold` and one more for each doubling past it. Ends as the limit
+// saturates at `u64::MAX`, which no load goes over
+fn doublings(load: u64, threshold: u64) -> u32 {
+    let mut doublings = 0;
+    let mut limit = threshold;
+    while load > limit {
+        doublings += 1;
+        limit = limit.saturating_mul(2);
+    }
+    doublings
+}
+
+// what a challenge's signature covers
+fn bound(claims: &str, path: &str, client_ip: Option<IpAddr>) -> String {
+    let client_ip = client_ip.map(|ip| ip.to_string()).unwrap_or_default();
+    format!("{}|{}|{}", claims, path, client_ip)
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ChallengeParams {
+    // the path of the protected route the challenge is for
+    path: String,
+}
+
+// a challenge for the client to solve before posting to `path`
+pub(crate) async fn issue_challenge(
+    State(state): State<Arc<AppState>>,
+    client_ip: Option<Extension<ClientIp>>,
+    Query(params): Query<ChallengeParams>,
+) -> Response {
+    let config = &state.config.proof_of_work;
+    if params.path.len() > MAX_PATH_LENGTH || !params.path.starts_with('/') {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": "path must be the path of a route" }))).into_response();
+    }
+
+    let client_ip = client_ip.and_then(|Extension(ClientIp(ip))| ip);
+    let (challenge, difficulty) = state.proof_of_work.issue(config, &params.path, client_ip);
+    let mut response = Json(json!({
+        // when disabled requests go through without a solution, so there is no need to solve it
+        "enabled": config.enabled,
+        "challenge": challenge,
+        "difficulty": difficulty,
+        "expires_in": config.expiry_secs,
+    }))
+    .into_response();
+    // every challenge is single use
+    response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+// refuses requests without a solved challenge for their path, see `ProofOfWork`
+pub(crate) async fn require_proof_of_work(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    if !state.config.proof_of_work.enabled {
+        return next.run(request).await;
+    }
+
+    let client_ip = request.extensions().get::<ClientIp>().and_then(|ClientIp(ip)| *ip);
+    let solution = request.headers().get(X_PROOF_OF_WORK).and_then(|value| value.to_str().ok());
+    match state.proof_of_work.verify(solution, request.uri().path(), client_ip) {
+        Ok(()) => next.run(request).await,
+        Err(reason) => {
+            metrics().proof_of_work_rejected.with_label_values(&[reason]).inc();
+            tracing::debug!("Refused {} without a valid proof of work, {}.", request.uri().path(), reason);
+            (StatusCode::FORBIDDEN, "A valid proof of work is needed, please try again.").into_response()
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "/comments";
+
+    fn solve(challenge: &str, difficulty: u32) -> String {
+        (0..)
+            .map(|counter| format!("{}:{}", challenge, counter))
+            .find(|solution| leading_zero_bits(digest(&SHA256, solution.as_bytes()).as_ref()) >= difficulty)
+            .unwrap()
+    }
+
+    #[test]
+    fn only_busy_clients_get_harder_challenges() {
+        let config = ProofOfWorkConfig {
+            difficulty: 4,
+            max_difficulty: 6,
+            load_threshold: 5,
+            ..ProofOfWorkConfig::default()
+        };
+        let proof_of_work = ProofOfWork::new();
+        let busy = Some("203.0.113.1".parse().unwrap());
+        let quiet = Some("203.0.113.2".parse().unwrap());
+
+        let difficulties: Vec<u32> = (0..25).map(|_| proof_of_work.issue(&config, PATH, busy).1).collect();
+        assert_eq!(difficulties[..5], [4; 5]);
+        assert_eq!(difficulties[5..10], [5; 5]);
+        // no harder than `max_difficulty`
+        assert_eq!(difficulties[10..], [6; 15]);
+        assert_eq!(proof_of_work.issue(&config, PATH, quiet).1, 4);
+    }
+
+    #[test]
+    fn load_spread_over_many_clients_raises_everyones_difficulty() {
+        let config = ProofOfWorkConfig {
+            difficulty: 4,
+            max_difficulty: 6,
+            global_load_threshold: 10,
+            ..ProofOfWorkConfig::default()
+        };
+        let proof_of_work = ProofOfWork::new();
+
+        let difficulties: Vec<u32> = (1..=20u8)
+            .map(|client| proof_of_work.issue(&config, PATH, Some([203, 0, 113, client].into())).1)
+            .collect();
+        assert_eq!(difficulties[..10], [4; 10]);
+        assert_eq!(difficulties[10..], [5; 10]);
+    }
+
+    #[test]
+    fn takes_each_solution_once_from_the_client_it_was_issued_to() {
+        let config = ProofOfWorkConfig {
+            difficulty: 4,
+            ..ProofOfWorkConfig::default()
+        };
+        let proof_of_work = ProofOfWork::new();
+        let client_ip = Some("203.0.113.1".parse().unwrap());
+        let (challenge, difficulty) = proof_of_work.issue(&config, PATH, client_ip);
+        let solution = solve(&challenge, difficulty);
+
+        let other_ip = Some("203.0.113.2".parse().unwrap());
+        assert_eq!(proof_of_work.verify(Some(&solution), PATH, other_ip), Err("invalid"));
+        assert_eq!(proof_of_work.verify(Some(&solution), "/react", client_ip), Err("invalid"));
+        assert_eq!(proof_of_work.verify(Some(&solution), PATH, client_ip), Ok(()));
+        assert_eq!(proof_of_work.verify(Some(&solution), PATH, client_ip), Err("spent"));
+        assert_eq!(proof_of_work.verify(None, PATH, client_ip), Err("missing"));
+    }
+}
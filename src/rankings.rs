@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    extract::State,
+    response::{Html, IntoResponse},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+use crate::{content::BlogPost, state::AppState};
+
+
+
+// post ids ranked by `Analytics::refresh_rankings`, best first. Requests only read the latest ranking,
+// nothing is worked out per request
+#[derive(Default)]
+pub(crate) struct Rankings {
+    // reads over the last week
+    pub(crate) popular: Vec<(String, u64)>,
+    // time decayed reads
+    pub(crate) trending: Vec<(String, f64)>,
+    // `None` until the first refresh
+    pub(crate) computed_at: Option<DateTime<Utc>>,
+}
+
+// the top `rankings.limit` of a ranking that are still published, ranked posts may since have been
+// removed
+fn top<T: Copy>(state: &AppState, ranking: &[(String, T)]) -> Vec<(Arc<BlogPost>, T)> {
+    ranking
+        .iter()
+        .filter_map(|(id, score)| Some((state.content.get(id)?, *score)))
+        .take(state.config.rankings.limit)
+        .collect()
+}
+
+#[derive(Template)]
+#[template(path = "ranking.html")]
+struct RankingTemplate {
+    heading: &'static str,
+    // each post with the url of its page
+    posts: Vec<(Arc<BlogPost>, String)>,
+}
+
+fn render_ranking(state: &AppState, heading: &'static str, posts: Vec<Arc<BlogPost>>) -> Html<String> {
+    let posts = posts
+        .into_iter()
+        .map(|post| {
+            let url = state.config.post_url(&post.id);
+            (post, url)
+        })
+        .collect();
+    Html(RankingTemplate { heading, posts }.render().unwrap())
+}
+
+// htmx fragment listing the most read posts this week, empty when nothing has been read
+pub(crate) async fn popular_posts(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let rankings = state.analytics.rankings();
+    let posts = top(&state, &rankings.popular).into_iter().map(|(post, _)| post).collect();
+    render_ranking(&state, "Most read this week", posts)
+}
+
+// htmx fragment listing the trending posts, empty when nothing has been read
+pub(crate) async fn trending_posts(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let rankings = state.analytics.rankings();
+    let posts = top(&state, &rankings.trending).into_iter().map(|(post, _)| post).collect();
+    render_ranking(&state, "Trending", posts)
+}
+
+fn post_json(post: &BlogPost, score_name: &str, score: Value) -> Value {
+    json!({
+        "id": post.id,
+        "title": post.title,
+        "published": post.published.to_rfc3339(),
+        score_name: score,
+    })
+}
+
+pub(crate) async fn api_popular_posts(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let rankings = state.analytics.rankings();
+    let posts: Vec<_> = top(&state, &rankings.popular)
+        .into_iter()
+        .map(|(post, reads)| post_json(&post, "reads", reads.into()))
+        .collect();
+    Json(json!({
+        "computed_at": rankings.computed_at.map(|computed_at| computed_at.to_rfc3339()),
+        "posts": posts,
+    }))
+}
+
+pub(crate) async fn api_trending_posts(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let rankings = state.analytics.rankings();
+    let posts: Vec<_> = top(&state, &rankings.trending)
+        .into_iter()
+        // scores are only meaningful relative to each other, more precision is just noise
+        .map(|(post, score)| post_json(&post, "score", ((score * 100.0).round() / 100.0).into()))
+        .collect();
+    Json(json!({
+        "computed_at": rankings.computed_at.map(|computed_at| computed_at.to_rfc3339()),
+        "posts": posts,
+    }))
+}
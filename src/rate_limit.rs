@@ -0,0 +1,224 @@
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    num::NonZeroU32,
+    sync::{Arc, Once, Weak},
+    time::Duration,
+};
+
+use askama::Template;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use governor::{
+    clock::{Clock, DefaultClock},
+    middleware::StateInformationMiddleware,
+    DefaultKeyedRateLimiter, Quota,
+};
+use ipnet::IpNet;
+use serde_json::json;
+
+use crate::{
+    client_ip::ClientIp,
+    config::{RateLimitConfig, RateLimitPolicy},
+    metrics::metrics,
+};
+
+
+
+// forgotten client buckets are dropped this often to keep memory bounded
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+const X_RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+const X_RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+
+static MISSING_CLIENT_ADDRESS: Once = Once::new();
+
+// requests without a client address share a single bucket instead of being rejected
+fn rate_limit_key<T>(request: &Request<T>) -> IpAddr {
+    if let Some(ClientIp(Some(ip))) = request.extensions().get::<ClientIp>() {
+        return *ip;
+    }
+    MISSING_CLIENT_ADDRESS.call_once(|| {
+        tracing::warn!(
+            "Request without a client address, these are rate limited together. \
+             Have the proxy in front of the unix socket set X-Forwarded-For or X-Real-IP."
+        );
+    });
+    IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+}
+
+
+// how a rejected request is answered, pages and fragments get html while the API gets JSON
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Rejection {
+    Html,
+    Json,
+}
+
+type KeyedLimiter = DefaultKeyedRateLimiter<IpAddr, StateInformationMiddleware>;
+
+// per client IP rate limiter for one group of routes, see `config::RateLimitConfig`
+pub(crate) struct RateLimiter {
+    group: &'static str,
+    policy: RateLimitPolicy,
+    // kept by the limiter for the group after a reload that leaves its policy as it is, so reloading
+    // doesn't hand every client a fresh burst
+    limiter: Arc<KeyedLimiter>,
+    allowlist: Arc<Vec<IpNet>>,
+    rejection: Rejection,
+}
+
+impl RateLimiter {
+    // a limiter with the clients' buckets of `previous` when it has the same policy, or else new ones
+    fn new(
+        group: &'static str,
+        policy: &RateLimitPolicy,
+        allowlist: Arc<Vec<IpNet>>,
+        rejection: Rejection,
+        previous: Option<&RateLimiter>,
+    ) -> Arc<Self> {
+        let limiter = match previous.filter(|previous| previous.policy == *policy) {
+            Some(previous) => previous.limiter.clone(),
+            None => {
+                // unwraps are fine as both values are checked to be non zero by `Config::validate`
+                let quota = Quota::with_period(Duration::from_millis(policy.period_ms))
+                    .unwrap()
+                    .allow_burst(NonZeroU32::new(policy.burst_size).unwrap());
+                let limiter = governor::RateLimiter::keyed(quota).with_middleware::<StateInformationMiddleware>();
+                let limiter = Arc::new(limiter);
+                // stops once the buckets are dropped, e.g. replaced by a reload changing the policy
+                let weak = Arc::downgrade(&limiter);
+                tokio::spawn(async move { cleanup(weak).await });
+                limiter
+            }
+        };
+
+        Arc::new(RateLimiter {
+            group,
+            policy: policy.clone(),
+            limiter,
+            allowlist,
+            rejection,
+        })
+    }
+}
+
+// the limiter of each group of routes. Built along with every state, each group keeping the buckets
+// it had in the state before unless its policy changed
+pub(crate) struct RateLimiters {
+    pub(crate) pages: Arc<RateLimiter>,
+    pub(crate) fragments: Arc<RateLimiter>,
+    pub(crate) api: Arc<RateLimiter>,
+    pub(crate) admin: Arc<RateLimiter>,
+    pub(crate) comments: Arc<RateLimiter>,
+}
+
+impl RateLimiters {
+    pub(crate) fn new(config: &RateLimitConfig, previous: Option<&RateLimiters>) -> Self {
+        let allowlist = Arc::new(config.allowlist_networks());
+        let limiter = |group, policy, rejection, pick: fn(&RateLimiters) -> &Arc<RateLimiter>| {
+            let previous = previous.map(|previous| pick(previous).as_ref());
+            RateLimiter::new(group, policy, allowlist.clone(), rejection, previous)
+        };
+        RateLimiters {
+            pages: limiter("pages", &config.pages, Rejection::Html, |limiters| &limiters.pages),
+            fragments: limiter("fragments", &config.fragments, Rejection::Html, |limiters| &limiters.fragments),
+            api: limiter("api", &config.api, Rejection::Json, |limiters| &limiters.api),
+            admin: limiter("admin", &config.admin, Rejection::Html, |limiters| &limiters.admin),
+            comments: limiter("comments", &config.comments, Rejection::Html, |limiters| &limiters.comments),
+        }
+    }
+}
+
+async fn cleanup(limiter: Weak<KeyedLimiter>) {
+    let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let Some(limiter) = limiter.upgrade() else {
+            return;
+        };
+        limiter.retain_recent();
+        limiter.shrink_to_fit();
+    }
+}
+
+pub(crate) async fn rate_limit(State(rate_limiter): State<Arc<RateLimiter>>, request: Request, next: Next) -> Response {
+    let ip = rate_limit_key(&request);
+    if rate_limiter.allowlist.iter().any(|network| network.contains(&ip)) {
+        return next.run(request).await;
+    }
+
+    match rate_limiter.limiter.check_key(&ip) {
+        Ok(snapshot) => {
+            let mut response = next.run(request).await;
+            let headers = response.headers_mut();
+            headers.insert(X_RATELIMIT_LIMIT, snapshot.quota().burst_size().get().into());
+            headers.insert(X_RATELIMIT_REMAINING, snapshot.remaining_burst_capacity().into());
+            response
+        }
+        Err(not_until) => {
+            metrics().rate_limited.with_label_values(&[rate_limiter.group]).inc();
+            // rounded up so a client waiting exactly this long is let through
+            let wait = not_until.wait_time_from(DefaultClock::default().now());
+            let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            tracing::debug!("Rate limited {} on {} for {}s.", ip, rate_limiter.group, retry_after);
+            too_many_requests(rate_limiter.rejection, retry_after)
+        }
+    }
+}
+
+
+#[derive(Template)]
+#[template(path = "429.html")]
+struct TooManyRequestsTemplate {
+    retry_after: u64,
+}
+
+fn too_many_requests(rejection: Rejection, retry_after: u64) -> Response {
+    let mut response = match rejection {
+        Rejection::Html => Html(TooManyRequestsTemplate { retry_after }.render().unwrap()).into_response(),
+        Rejection::Json => Json(json!({
+            "error": "too many requests",
+            "retry_after": retry_after,
+        }))
+        .into_response(),
+    };
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    response
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reloads_keep_buckets_unless_the_policy_changes() {
+        let mut config = RateLimitConfig {
+            pages: RateLimitPolicy {
+                period_ms: 60_000,
+                burst_size: 1,
+            },
+            ..RateLimitConfig::default()
+        };
+        let ip = "203.0.113.1".parse().unwrap();
+        let limiters = RateLimiters::new(&config, None);
+        assert!(limiters.pages.limiter.check_key(&ip).is_ok());
+        assert!(limiters.pages.limiter.check_key(&ip).is_err());
+
+        let reloaded = RateLimiters::new(&config, Some(&limiters));
+        assert!(reloaded.pages.limiter.check_key(&ip).is_err());
+
+        config.pages.burst_size = 2;
+        let changed = RateLimiters::new(&config, Some(&reloaded));
+        assert!(changed.pages.limiter.check_key(&ip).is_ok());
+        // the other groups are left as they were
+        assert!(Arc::ptr_eq(&changed.api.limiter, &limiters.api.limiter));
+    }
+}
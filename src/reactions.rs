@@ -0,0 +1,300 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    io::Write,
+    net::IpAddr,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path as FilePath, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use askama::Template;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Extension, Form, Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    analytics::write_atomically, client_ip::ClientIp, config::ReactionsConfig, metrics::metrics,
+    services::handler_404, state::AppState,
+};
+
+
+
+// everything kept in `reactions.path`
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct Stored {
+    // post id -> visitor -> the one reaction they chose. Visitors are an HMAC of the post, their address
+    // and user agent under the key in `reactions.key_path`, so telling who reacted takes that key too
+    posts: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+// reader reactions on every post. Counts are kept added up in memory and updated on every reaction,
+// so serving them never goes through the visitors. Shared by every state so reactions survive reloads
+pub(crate) struct Reactions {
+    path: PathBuf,
+    key: hmac::Key,
+    stored: Mutex<Stored>,
+    // post id -> reaction -> visitors who chose it
+    counts: Mutex<HashMap<String, HashMap<String, u64>>>,
+}
+
+impl Reactions {
+    pub(crate) fn load(config: &ReactionsConfig) -> Result<Arc<Self>, String> {
+        let stored: Stored = match fs::read(&config.path) {
+            Ok(raw) => serde_json::from_slice(&raw)
+                .map_err(|err| format!("failed to read reactions from {}: {}", config.path.display(), err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Stored::default(),
+            Err(err) => return Err(format!("failed to read reactions from {}: {}", config.path.display(), err)),
+        };
+        let key = load_key(&config.key_path)
+            .map_err(|err| format!("failed to read the reactions key from {}: {}", config.key_path.display(), err))?;
+
+        let mut counts: HashMap<String, HashMap<String, u64>> = HashMap::new();
+        for (post, visitors) in &stored.posts {
+            let post_counts = counts.entry(post.clone()).or_default();
+            for reaction in visitors.values() {
+                *post_counts.entry(reaction.clone()).or_default() += 1;
+            }
+        }
+
+        Ok(Arc::new(Reactions {
+            path: config.path.clone(),
+            key: hmac::Key::new(hmac::HMAC_SHA256, &key),
+            stored: Mutex::new(stored),
+            counts: Mutex::new(counts),
+        }))
+    }
+
+    fn visitor(&self, post: &str, client_ip: Option<IpAddr>, headers: &HeaderMap) -> String {
+        let mut context = hmac::Context::with_key(&self.key);
+        context.update(post.as_bytes());
+        context.update(b"\0");
+        context.update(client_ip.map(|ip| ip.to_string()).unwrap_or_default().as_bytes());
+        context.update(b"\0");
+        context.update(headers.get(header::USER_AGENT).map(|value| value.as_bytes()).unwrap_or_default());
+        // truncated, 128 bits is plenty to tell visitors apart
+        URL_SAFE_NO_PAD.encode(&context.sign().as_ref()[..16])
+    }
+
+    // the visitor's reaction to a post, if any
+    fn chosen(&self, post: &str, visitor: &str) -> Option<String> {
+        let stored = self.stored.lock().unwrap();
+        stored.posts.get(post).and_then(|visitors| visitors.get(visitor)).cloned()
+    }
+
+    // sets the visitor's one reaction to a post, or takes it back if it is the one they already chose
+    fn react(&self, post: &str, visitor: String, reaction: &str) -> Option<String> {
+        let mut stored = self.stored.lock().unwrap();
+        let mut counts = self.counts.lock().unwrap();
+        let visitors = stored.posts.entry(post.to_string()).or_default();
+        let post_counts = counts.entry(post.to_string()).or_default();
+
+        let previous = visitors.remove(&visitor);
+        if let Some(previous) = &previous {
+            if let Some(count) = post_counts.get_mut(previous) {
+                *count = count.saturating_sub(1);
+            }
+        }
+        if previous.as_deref() == Some(reaction) {
+            return None;
+        }
+        visitors.insert(visitor, reaction.to_string());
+        *post_counts.entry(reaction.to_string()).or_default() += 1;
+        Some(reaction.to_string())
+    }
+
+    // counts of each of the configured reactions, in order
+    fn counts(&self, config: &ReactionsConfig, post: &str) -> Vec<(String, u64)> {
+        let counts = self.counts.lock().unwrap();
+        let post_counts = counts.get(post);
+        config
+            .emojis
+            .iter()
+            .map(|emoji| {
+                let count = post_counts.and_then(|counts| counts.get(emoji)).copied().unwrap_or_default();
+                (emoji.clone(), count)
+            })
+            .collect()
+    }
+
+    fn save(&self) {
+        // unwrap is fine as reactions are plain strings
+        let raw = serde_json::to_vec(&*self.stored.lock().unwrap()).unwrap();
+        if let Err(err) = write_atomically(&self.path, &raw) {
+            tracing::error!("Failed to write reactions to {}. Error: {:#?}", self.path.display(), err);
+        }
+    }
+}
+
+// the key from `path`, a new random one written there the first time. Only the owner may read it
+fn load_key(path: &FilePath) -> Result<Vec<u8>, String> {
+    let encoded = match fs::read_to_string(path) {
+        Ok(encoded) => encoded.trim().to_string(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let mut key = [0; 32];
+            // unwrap is fine as the system random source only fails when the OS has none
+            SystemRandom::new().fill(&mut key).unwrap();
+            let encoded = URL_SAFE_NO_PAD.encode(key);
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .map_err(|err| err.to_string())?;
+            file.write_all(encoded.as_bytes()).map_err(|err| err.to_string())?;
+            encoded
+        }
+        Err(err) => return Err(err.to_string()),
+    };
+    match URL_SAFE_NO_PAD.decode(&encoded) {
+        Ok(key) if !key.is_empty() => Ok(key),
+        Ok(_) => Err("the key is empty".to_string()),
+        Err(err) => Err(format!("bad key, {}", err)),
+    }
+}
+
+// writes the reactions without holding up the runtime
+async fn persist(reactions: &Arc<Reactions>) {
+    let reactions = reactions.clone();
+    if let Err(err) = tokio::task::spawn_blocking(move || reactions.save()).await {
+        tracing::error!("Failed to write reactions. Error: {:#?}", err);
+    }
+}
+
+
+#[derive(Template)]
+#[template(path = "reactions.html")]
+struct ReactionsTemplate<'a> {
+    post: &'a str,
+    // each reaction with its count and whether it is the visitor's
+    reactions: Vec<(String, u64, bool)>,
+}
+
+fn render_reactions(state: &AppState, post: &str, chosen: Option<&str>) -> Response {
+    let reactions = state
+        .reactions
+        .counts(&state.config.reactions, post)
+        .into_iter()
+        .map(|(emoji, count)| {
+            let is_chosen = chosen == Some(emoji.as_str());
+            (emoji, count, is_chosen)
+        })
+        .collect();
+    Html(ReactionsTemplate { post, reactions }.render().unwrap()).into_response()
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ReactionsParams {
+    post: String,
+}
+
+// htmx fragment with the reactions under a post. Loaded on its own so cached posts don't go stale
+// with every reaction, and empty when reactions are disabled
+pub(crate) async fn get_reactions(
+    State(state): State<Arc<AppState>>,
+    client_ip: Option<Extension<ClientIp>>,
+    Query(params): Query<ReactionsParams>,
+    headers: HeaderMap,
+) -> Response {
+    if !state.config.reactions.enabled {
+        return Html(String::new()).into_response();
+    }
+    let Some(post) = state.content.get(&params.post) else {
+        return handler_404().await.into_response();
+    };
+
+    let client_ip = client_ip.and_then(|Extension(ClientIp(ip))| ip);
+    let visitor = state.reactions.visitor(&post.id, client_ip, &headers);
+    let chosen = state.reactions.chosen(&post.id, &visitor);
+    render_reactions(&state, &post.id, chosen.as_deref())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct React {
+    post: String,
+    emoji: String,
+}
+
+// adds, changes or takes back the visitor's reaction, answering with the updated counts
+pub(crate) async fn react(
+    State(state): State<Arc<AppState>>,
+    client_ip: Option<Extension<ClientIp>>,
+    headers: HeaderMap,
+    Form(form): Form<React>,
+) -> Response {
+    let config = &state.config.reactions;
+    let is_known = config.emojis.contains(&form.emoji);
+    if !config.enabled || !is_known || state.content.get(&form.post).is_none() {
+        return handler_404().await.into_response();
+    }
+
+    let client_ip = client_ip.and_then(|Extension(ClientIp(ip))| ip);
+    let visitor = state.reactions.visitor(&form.post, client_ip, &headers);
+    let chosen = state.reactions.react(&form.post, visitor, &form.emoji);
+    persist(&state.reactions).await;
+    metrics().reactions.with_label_values(&[if chosen.is_some() { "added" } else { "removed" }]).inc();
+    render_reactions(&state, &form.post, chosen.as_deref())
+}
+
+// counts of each configured reaction on a post
+pub(crate) async fn api_reactions(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Response {
+    let config = &state.config.reactions;
+    if !config.enabled || state.content.get(&id).is_none() {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "no such post" }))).into_response();
+    }
+    let reactions: serde_json::Map<String, serde_json::Value> = state
+        .reactions
+        .counts(config, &id)
+        .into_iter()
+        .map(|(emoji, count)| (emoji, count.into()))
+        .collect();
+    Json(json!({ "post": id, "reactions": reactions })).into_response()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn config(dir: &TempDir) -> ReactionsConfig {
+        ReactionsConfig {
+            path: dir.join("reactions.json"),
+            key_path: dir.join("reactions.key"),
+            ..ReactionsConfig::default()
+        }
+    }
+
+    #[test]
+    fn key_is_kept_out_of_the_reactions_file() {
+        let dir = TempDir::new();
+        let config = config(&dir);
+        let reactions = Reactions::load(&config).unwrap();
+        let visitor = reactions.visitor("hello", None, &HeaderMap::new());
+        reactions.react("hello", visitor.clone(), "👍");
+        reactions.save();
+
+        let saved: serde_json::Value = serde_json::from_slice(&fs::read(&config.path).unwrap()).unwrap();
+        assert!(saved.get("key").is_none());
+        let mode = fs::metadata(&config.key_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // the same key is used again after a restart
+        let reloaded = Reactions::load(&config).unwrap();
+        assert_eq!(reloaded.visitor("hello", None, &HeaderMap::new()), visitor);
+        assert_eq!(reloaded.chosen("hello", &visitor).as_deref(), Some("👍"));
+    }
+}
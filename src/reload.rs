@@ -0,0 +1,105 @@
+use std::{fs, path::PathBuf, sync::Arc, time::Duration};
+
+use clap::ArgMatches;
+use notify::{Event, RecursiveMode, Watcher};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+
+use crate::{config::Config, state::AppState};
+
+
+
+// editors tend to write a file in several steps, wait for things to settle before reloading
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+// reloads the config and content whenever either changes on disk or on SIGHUP, handing each new
+// snapshot to `apply`. A failed reload keeps the current snapshot and logs the error
+pub(crate) async fn watch<F>(matches: ArgMatches, mut current: Arc<AppState>, apply: F)
+where
+    F: Fn(Arc<AppState>) + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    // notify reports absolute paths, so compare against canonical ones
+    let config_path = matches
+        .get_one::<PathBuf>("config")
+        .and_then(|path| fs::canonicalize(path).ok());
+    let content_dir = fs::canonicalize(&current.config.content.dir)
+        .unwrap_or_else(|_| current.config.content.dir.clone());
+
+    let watched_config = config_path.clone();
+    let watched_content = content_dir.clone();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        if event.kind.is_access() {
+            return;
+        }
+        let relevant = event.paths.iter().any(|path| {
+            path.starts_with(&watched_content) || watched_config.as_ref().is_some_and(|config| path == config)
+        });
+        if relevant {
+            let _ = tx.send(());
+        }
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => Some(watcher),
+        Err(err) => {
+            tracing::error!("Failed to start file watcher, only SIGHUP will trigger a reload. Error: {:#?}", err);
+            None
+        }
+    };
+    if let Some(watcher) = watcher.as_mut() {
+        if let Err(err) = watcher.watch(&content_dir, RecursiveMode::Recursive) {
+            tracing::error!("Failed to watch {}. Error: {:#?}", content_dir.display(), err);
+        }
+        // the parent directory is watched as editors often replace the file rather than write to it
+        if let Some(parent) = config_path.as_ref().and_then(|path| path.parent()) {
+            if let Err(err) = watcher.watch(parent, RecursiveMode::NonRecursive) {
+                tracing::error!("Failed to watch {}. Error: {:#?}", parent.display(), err);
+            }
+        }
+    }
+
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                tracing::info!("SIGHUP received. Reloading...");
+            },
+            Some(()) = rx.recv() => {
+                tokio::time::sleep(DEBOUNCE).await;
+                while rx.try_recv().is_ok() {}
+                tracing::info!("Config or content changed. Reloading...");
+            },
+        }
+
+        match reload(&matches, &current) {
+            Ok(state) => {
+                current = Arc::new(state);
+                apply(current.clone());
+                tracing::info!("Reload complete.");
+            }
+            Err(err) => {
+                tracing::error!("Reload failed, keeping the current config and content. Error: {}", err);
+            }
+        }
+    }
+}
+
+fn reload(matches: &ArgMatches, current: &AppState) -> Result<AppState, String> {
+    let mut config = Config::load(matches)?;
+    for key in Config::unknown_env_keys() {
+        tracing::warn!("Ignoring environment variable for unknown setting {}.", key);
+    }
+
+    let changed = config.retain_startup_settings(&current.config);
+    if !changed.is_empty() {
+        tracing::warn!("Changes to {} only take effect after a restart.", changed.join(", "));
+    }
+
+    AppState::reload(config, current)
+}
@@ -1,14 +1,23 @@
 
-use std::fmt;
+use std::sync::Arc;
 
 use askama::Template;
 use axum::{
-    http::{HeaderMap, StatusCode},
-    response::{Html, IntoResponse},
-    extract::Query,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Response},
+    extract::{Path, Query, State},
 };
 use serde::Deserialize;
-use uuid::Uuid;
+
+use crate::{
+    activitypub::{article_response, wants_activity_json},
+    analytics::PageView,
+    config::LinkConfig,
+    content::BlogPost,
+    metrics::metrics,
+    page_cache::{Page, PageKey, BLOG_POST_ROUTE, POST_ROUTE},
+    state::AppState,
+};
 
 
 
@@ -24,128 +33,159 @@ pub(crate) async fn handler_404() -> impl IntoResponse {
 
 #[derive(Template)]
 #[template(path = "index.html")]
-struct IndexTemplate;
+struct IndexTemplate<'a> {
+    links: &'a [LinkConfig],
+}
 
 
-pub(crate) async fn index() -> Html<String> {
+pub(crate) async fn index(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
 
-    let index = IndexTemplate;
-        
-    Html(index.render().unwrap())
+    let key = PageKey::new("/", None, &headers);
+    let page = state.page_cache.get_or_render(&state, key, || {
+        let index = IndexTemplate {
+            links: &state.config.links,
+        };
+        Page::new(index.render().unwrap())
+    });
+
+    let mut response = page.to_response();
+    response.extensions_mut().insert(PageView { route: "/", post: None });
+    response
 }
 
 
 
 #[derive(Deserialize)]
 pub(crate) struct RedirectParams {
-    pub(crate) target: RedirectTarget
+    // name of a link in `config::Config::links`
+    pub(crate) target: String
 }
 
-#[derive(Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub(crate) enum RedirectTarget {
-    Github,
-    LinkedIn
-}
-
-impl fmt::Display for RedirectTarget {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let url = match &self {
-            RedirectTarget::Github => "https://github.com/alixmacdonald10",
-            RedirectTarget::LinkedIn => "https://linkedin.com/in/alixmac"
-        }; 
-        write!(f, "{}", url)
-    }
-}
-
-pub(crate) async fn redirect(params: Query<RedirectParams>) -> impl IntoResponse {
+// only links from the config can be redirected to, anything else is a 404 rather than an open redirect.
+// htmx would follow a redirect status itself and trip over CORS, so it gets a 200 and `HX-Redirect`
+// while plain links get a 302. Neither is cached so every click is counted
+pub(crate) async fn redirect(
+    State(state): State<Arc<AppState>>,
+    params: Query<RedirectParams>,
+    request_headers: HeaderMap,
+) -> Response {
      
-    let url = format!("{}", params.target);
-    tracing::debug!("Redirecting to {}", &url);
+    let Some(link) = state.config.link(&params.target) else {
+        return handler_404().await.into_response();
+    };
+    tracing::debug!("Redirecting to {}", &link.url);
+    state.analytics.record_click(&state.config, &link.name, &request_headers);
     
+    // unwrap is fine as the url is checked by `Config::validate`
+    let url: HeaderValue = link.url.parse().unwrap();
     let mut headers = HeaderMap::new();
-    headers.insert("HX-Redirect", url.parse().unwrap());
-    (StatusCode::PERMANENT_REDIRECT, headers)
-}
-
-
-struct BlogPost {
-    id: Uuid,
-    title: String,
-    content: String,
+    headers.insert("HX-Redirect", url.clone());
+    headers.insert(header::LOCATION, url);
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    let status = match request_headers.get("hx-request") {
+        Some(_) => StatusCode::OK,
+        None => StatusCode::FOUND,
+    };
+    (status, headers).into_response()
 }
 
 
 #[derive(Template)]
 #[template(path = "blog_post.html")]
 struct BlogPostTemplate {
-    blog_post: BlogPost
+    blog_post: Arc<BlogPost>
 }
 
 #[derive(Deserialize, Debug)]
 pub(crate) struct GetBlogPostParams {
-    id: Option<Uuid>,
+    // the post already on the page, the next older post is returned
+    id: Option<String>,
 }
 
 
-pub(crate) async fn get_blog_post(Query(params): Query<GetBlogPostParams>) -> Html<String> {
+pub(crate) async fn get_blog_post(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<GetBlogPostParams>,
+    headers: HeaderMap,
+) -> Response {
     
-    let blog_post = match params.id {
-        None => {
-            tracing::debug!("No parameters passed. Getting latest blog post...");
-
-            // TODO: get first 10 blog posts from db  and store in cache return latest post
-            let content = "
-Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua. Et ultrices neque ornare aenean. Sit amet volutpat consequat mauris nunc congue. Nunc scelerisque viverra mauris in. Tempus imperdiet nulla malesuada pellentesque elit eget gravida cum. Eget nulla facilisi etiam dignissim diam quis enim lobortis scelerisque. Viverra accumsan in nisl nisi scelerisque. Feugiat sed lectus vestibulum mattis. Mi tempus imperdiet nulla malesuada. Leo vel fringilla est ullamcorper eget. Cursus sit amet dictum sit amet justo. Magna eget est lorem ipsum dolor sit amet. Fermentum et sollicitudin ac orci phasellus egestas tellus. Cursus metus aliquam eleifend mi in. Luctus venenatis lectus magna fringilla urna porttitor rhoncus dolor purus.
-
-Tempor orci dapibus ultrices in iaculis nunc sed augue. Mi sit amet mauris commodo quis imperdiet massa tincidunt nunc. Pharetra convallis posuere morbi leo urna molestie. Erat nam at lectus urna duis convallis convallis tellus. Odio eu feugiat pretium nibh. Sapien pellentesque habitant morbi tristique senectus et. Urna cursus eget nunc scelerisque viverra mauris in aliquam. Commodo quis imperdiet massa tincidunt nunc pulvinar sapien et ligula. Tellus orci ac auctor augue mauris augue. Cras ornare arcu dui vivamus arcu felis bibendum. Faucibus interdum posuere lorem ipsum dolor. Et malesuada fames ac turpis egestas sed tempus. Ipsum dolor sit amet consectetur adipiscing. Mi bibendum neque egestas congue quisque. Semper auctor neque vitae tempus. Sit amet est placerat in. Habitant morbi tristique senectus et netus.
-
-Nisl rhoncus mattis rhoncus urna neque viverra justo nec. Ultrices sagittis orci a scelerisque purus. Proin libero nunc consequat interdum varius. Ac feugiat sed lectus vestibulum. Id venenatis a condimentum vitae sapien pellentesque habitant. Tortor at auctor urna nunc id cursus metus aliquam eleifend. Vel orci porta non pulvinar neque laoreet suspendisse interdum consectetur. In dictum non consectetur a erat nam at lectus. Vitae aliquet nec ullamcorper sit amet risus nullam eget. Urna molestie at elementum eu facilisis sed odio morbi. Cursus eget nunc scelerisque viverra mauris in aliquam. Ut faucibus pulvinar elementum integer enim neque. Augue mauris augue neque gravida. Faucibus nisl tincidunt eget nullam non nisi. Interdum consectetur libero id faucibus nisl tincidunt eget nullam non. In tellus integer feugiat scelerisque varius morbi.
-
-Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua. Et ultrices neque ornare aenean. Sit amet volutpat consequat mauris nunc congue. Nunc scelerisque viverra mauris in. Tempus imperdiet nulla malesuada pellentesque elit eget gravida cum. Eget nulla facilisi etiam dignissim diam quis enim lobortis scelerisque. Viverra accumsan in nisl nisi scelerisque. Feugiat sed lectus vestibulum mattis. Mi tempus imperdiet nulla malesuada. Leo vel fringilla est ullamcorper eget. Cursus sit amet dictum sit amet justo. Magna eget est lorem ipsum dolor sit amet. Fermentum et sollicitudin ac orci phasellus egestas tellus. Cursus metus aliquam eleifend mi in. Luctus venenatis lectus magna fringilla urna porttitor rhoncus dolor purus.
-
-Tempor orci dapibus ultrices in iaculis nunc sed augue. Mi sit amet mauris commodo quis imperdiet massa tincidunt nunc. Pharetra convallis posuere morbi leo urna molestie. Erat nam at lectus urna duis convallis convallis tellus. Odio eu feugiat pretium nibh. Sapien pellentesque habitant morbi tristique senectus et. Urna cursus eget nunc scelerisque viverra mauris in aliquam. Commodo quis imperdiet massa tincidunt nunc pulvinar sapien et ligula. Tellus orci ac auctor augue mauris augue. Cras ornare arcu dui vivamus arcu felis bibendum. Faucibus interdum posuere lorem ipsum dolor. Et malesuada fames ac turpis egestas sed tempus. Ipsum dolor sit amet consectetur adipiscing. Mi bibendum neque egestas congue quisque. Semper auctor neque vitae tempus. Sit amet est placerat in. Habitant morbi tristique senectus et netus.
-
-Nisl rhoncus mattis rhoncus urna neque viverra justo nec. Ultrices sagittis orci a scelerisque purus. Proin libero nunc consequat interdum varius. Ac feugiat sed lectus vestibulum. Id venenatis a condimentum vitae sapien pellentesque habitant. Tortor at auctor urna nunc id cursus metus aliquam eleifend. Vel orci porta non pulvinar neque laoreet suspendisse interdum consectetur. In dictum non consectetur a erat nam at lectus. Vitae aliquet nec ullamcorper sit amet risus nullam eget. Urna molestie at elementum eu facilisis sed odio morbi. Cursus eget nunc scelerisque viverra mauris in aliquam. Ut faucibus pulvinar elementum integer enim neque. Augue mauris augue neque gravida. Faucibus nisl tincidunt eget nullam non nisi. Interdum consectetur libero id faucibus nisl tincidunt eget nullam non. In tellus integer feugiat scelerisque varius morbi.
-            ".to_string();
-                
-            BlogPost {
-                id: Uuid::now_v7(),
-                title: "LATEST Blog Post".to_string(),
-                content: content.to_owned()
-            }
-        },
-        Some(id) => {
-            tracing::debug!("Parameters passed. Getting blog post...");
-            tracing::info!("got id {:#?}", id);
-
-            // TODO: get post from cache and update cache from queue to preload next post 
-            let content = format!("
-POST RECEIVED ID: {:#?}
-POST LOADED AT: {:#?}
-
+    match &params.id {
+        None => tracing::debug!("No parameters passed. Getting latest blog post..."),
+        Some(id) => tracing::debug!("Parameters passed. Getting blog post after {}...", id),
+    }
+    let blog_post = state.content.next(params.id.as_deref());
 
-Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua. Et ultrices neque ornare aenean. Sit amet volutpat consequat mauris nunc congue. Nunc scelerisque viverra mauris in. Tempus imperdiet nulla malesuada pellentesque elit eget gravida cum. Eget nulla facilisi etiam dignissim diam quis enim lobortis scelerisque. Viverra accumsan in nisl nisi scelerisque. Feugiat sed lectus vestibulum mattis. Mi tempus imperdiet nulla malesuada. Leo vel fringilla est ullamcorper eget. Cursus sit amet dictum sit amet justo. Magna eget est lorem ipsum dolor sit amet. Fermentum et sollicitudin ac orci phasellus egestas tellus. Cursus metus aliquam eleifend mi in. Luctus venenatis lectus magna fringilla urna porttitor rhoncus dolor purus.
+    // nothing more to show, htmx leaves the page as it is on a 204
+    let Some(blog_post) = blog_post else {
+        return StatusCode::NO_CONTENT.into_response();
+    };
 
-Tempor orci dapibus ultrices in iaculis nunc sed augue. Mi sit amet mauris commodo quis imperdiet massa tincidunt nunc. Pharetra convallis posuere morbi leo urna molestie. Erat nam at lectus urna duis convallis convallis tellus. Odio eu feugiat pretium nibh. Sapien pellentesque habitant morbi tristique senectus et. Urna cursus eget nunc scelerisque viverra mauris in aliquam. Commodo quis imperdiet massa tincidunt nunc pulvinar sapien et ligula. Tellus orci ac auctor augue mauris augue. Cras ornare arcu dui vivamus arcu felis bibendum. Faucibus interdum posuere lorem ipsum dolor. Et malesuada fames ac turpis egestas sed tempus. Ipsum dolor sit amet consectetur adipiscing. Mi bibendum neque egestas congue quisque. Semper auctor neque vitae tempus. Sit amet est placerat in. Habitant morbi tristique senectus et netus.
+    metrics().posts_served.inc();
+    let view = PageView {
+        route: BLOG_POST_ROUTE,
+        post: Some(blog_post.id.clone()),
+    };
+    let key = PageKey::new(BLOG_POST_ROUTE, params.id, &headers);
+    let page = state.page_cache.get_or_render(&state, key, || {
+        // unwrap is fine as http dates are plain ascii
+        let last_modified = HeaderValue::from_str(&httpdate::fmt_http_date(blog_post.updated.into())).unwrap();
+        let id = blog_post.id.clone();
+        let blog_post_template = BlogPostTemplate {blog_post};
+        Page::new(blog_post_template.render().unwrap()).with_post(id, last_modified)
+    });
+
+    let mut response = page.to_response();
+    response.extensions_mut().insert(view);
+    response
+}
 
-Nisl rhoncus mattis rhoncus urna neque viverra justo nec. Ultrices sagittis orci a scelerisque purus. Proin libero nunc consequat interdum varius. Ac feugiat sed lectus vestibulum. Id venenatis a condimentum vitae sapien pellentesque habitant. Tortor at auctor urna nunc id cursus metus aliquam eleifend. Vel orci porta non pulvinar neque laoreet suspendisse interdum consectetur. In dictum non consectetur a erat nam at lectus. Vitae aliquet nec ullamcorper sit amet risus nullam eget. Urna molestie at elementum eu facilisis sed odio morbi. Cursus eget nunc scelerisque viverra mauris in aliquam. Ut faucibus pulvinar elementum integer enim neque. Augue mauris augue neque gravida. Faucibus nisl tincidunt eget nullam non nisi. Interdum consectetur libero id faucibus nisl tincidunt eget nullam non. In tellus integer feugiat scelerisque varius morbi.
 
-Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua. Et ultrices neque ornare aenean. Sit amet volutpat consequat mauris nunc congue. Nunc scelerisque viverra mauris in. Tempus imperdiet nulla malesuada pellentesque elit eget gravida cum. Eget nulla facilisi etiam dignissim diam quis enim lobortis scelerisque. Viverra accumsan in nisl nisi scelerisque. Feugiat sed lectus vestibulum mattis. Mi tempus imperdiet nulla malesuada. Leo vel fringilla est ullamcorper eget. Cursus sit amet dictum sit amet justo. Magna eget est lorem ipsum dolor sit amet. Fermentum et sollicitudin ac orci phasellus egestas tellus. Cursus metus aliquam eleifend mi in. Luctus venenatis lectus magna fringilla urna porttitor rhoncus dolor purus.
+#[derive(Template)]
+#[template(path = "post.html")]
+struct PostTemplate<'a> {
+    links: &'a [LinkConfig],
+    blog_post: Arc<BlogPost>,
+    // advertise the webmention endpoint
+    webmention: bool,
+}
 
-Tempor orci dapibus ultrices in iaculis nunc sed augue. Mi sit amet mauris commodo quis imperdiet massa tincidunt nunc. Pharetra convallis posuere morbi leo urna molestie. Erat nam at lectus urna duis convallis convallis tellus. Odio eu feugiat pretium nibh. Sapien pellentesque habitant morbi tristique senectus et. Urna cursus eget nunc scelerisque viverra mauris in aliquam. Commodo quis imperdiet massa tincidunt nunc pulvinar sapien et ligula. Tellus orci ac auctor augue mauris augue. Cras ornare arcu dui vivamus arcu felis bibendum. Faucibus interdum posuere lorem ipsum dolor. Et malesuada fames ac turpis egestas sed tempus. Ipsum dolor sit amet consectetur adipiscing. Mi bibendum neque egestas congue quisque. Semper auctor neque vitae tempus. Sit amet est placerat in. Habitant morbi tristique senectus et netus.
+// a single post on a page of its own, the link other sites use for it
+pub(crate) async fn get_post(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(blog_post) = state.content.get(&id) else {
+        return handler_404().await.into_response();
+    };
 
-Nisl rhoncus mattis rhoncus urna neque viverra justo nec. Ultrices sagittis orci a scelerisque purus. Proin libero nunc consequat interdum varius. Ac feugiat sed lectus vestibulum. Id venenatis a condimentum vitae sapien pellentesque habitant. Tortor at auctor urna nunc id cursus metus aliquam eleifend. Vel orci porta non pulvinar neque laoreet suspendisse interdum consectetur. In dictum non consectetur a erat nam at lectus. Vitae aliquet nec ullamcorper sit amet risus nullam eget. Urna molestie at elementum eu facilisis sed odio morbi. Cursus eget nunc scelerisque viverra mauris in aliquam. Ut faucibus pulvinar elementum integer enim neque. Augue mauris augue neque gravida. Faucibus nisl tincidunt eget nullam non nisi. Interdum consectetur libero id faucibus nisl tincidunt eget nullam non. In tellus integer feugiat scelerisque varius morbi.
-            ", id, std::time::Instant::now());
-                
-            BlogPost {
-                id: Uuid::now_v7(),
-                title: "Blog Post".to_string(),
-                content: content.to_owned()
-            }
+    // fediverse servers look a post up at its own url
+    if wants_activity_json(&headers) {
+        if let Some(response) = article_response(&state, &blog_post) {
+            return response;
         }
-    };
+    }
 
-    let blog_post_template = BlogPostTemplate {blog_post};
-    Html(blog_post_template.render().unwrap())
+    metrics().posts_served.inc();
+    let key = PageKey::new(POST_ROUTE, Some(id.clone()), &headers);
+    let page = state.page_cache.get_or_render(&state, key, || {
+        // unwrap is fine as http dates are plain ascii
+        let last_modified = HeaderValue::from_str(&httpdate::fmt_http_date(blog_post.updated.into())).unwrap();
+        let post_template = PostTemplate {
+            links: &state.config.links,
+            blog_post,
+            webmention: state.config.webmention.enabled,
+        };
+        Page::new(post_template.render().unwrap()).with_post(id.clone(), last_modified)
+    });
+
+    let mut response = page.to_response();
+    if state.config.webmention.enabled {
+        response.headers_mut().insert(header::LINK, HeaderValue::from_static("</webmention>; rel=\"webmention\""));
+    }
+    if state.config.activitypub.enabled {
+        response.headers_mut().append(header::VARY, HeaderValue::from_static("accept"));
+    }
+    response.extensions_mut().insert(PageView { route: POST_ROUTE, post: Some(id) });
+    response
 }
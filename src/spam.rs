@@ -0,0 +1,163 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{hmac, rand::SystemRandom};
+use serde::{Deserialize, Serialize};
+
+
+
+// tokens outside these lengths are mostly noise, e.g. ids or base64
+const MIN_TOKEN_LENGTH: usize = 2;
+const MAX_TOKEN_LENGTH: usize = 30;
+
+// counted as a token of its own, links being the point of most spam
+const LINK_TOKEN: &str = "__link__";
+
+// signs when each comment form was rendered, so a submission can't claim to be older than it is.
+// The key only lives as long as the process, forms from before a restart are refused as too quick
+pub(crate) struct FormStamps {
+    key: hmac::Key,
+}
+
+impl FormStamps {
+    pub(crate) fn new() -> Self {
+        // unwrap is fine as the system random source only fails when the OS has none
+        FormStamps {
+            key: hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()).unwrap(),
+        }
+    }
+
+    // `<unix seconds>.<signature>`, sent back with the form
+    pub(crate) fn issue(&self) -> String {
+        let issued = unix_now().to_string();
+        let signature = hmac::sign(&self.key, issued.as_bytes());
+        format!("{}.{}", issued, URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    // whether a stamp is genuine and at least `min_secs` old
+    pub(crate) fn is_old_enough(&self, stamp: &str, min_secs: u64) -> bool {
+        let Some((issued, signature)) = stamp.split_once('.') else {
+            return false;
+        };
+        let Ok(signature) = URL_SAFE_NO_PAD.decode(signature) else {
+            return false;
+        };
+        if hmac::verify(&self.key, issued.as_bytes(), &signature).is_err() {
+            return false;
+        }
+        issued
+            .parse::<u64>()
+            .is_ok_and(|issued| unix_now().saturating_sub(issued) >= min_secs)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// links in a comment, written as markdown or just pasted in. A www. address is only counted when it
+// has no scheme, otherwise it was counted by its scheme already
+pub(crate) fn count_links(text: &str) -> usize {
+    let text = text.to_ascii_lowercase();
+    let with_scheme = text.matches("http://").count() + text.matches("https://").count();
+    with_scheme + text.matches("www.").count() - text.matches("://www.").count()
+}
+
+// distinct words of a comment and its author's name, which is where a lot of spam puts its keywords
+fn tokens(author: &str, body: &str) -> BTreeSet<String> {
+    let mut tokens = BTreeSet::new();
+    for (prefix, text) in [("author:", author), ("", body)] {
+        for word in text.split(|c: char| !c.is_alphanumeric() && c != '\'') {
+            let word = word.trim_matches('\'').to_lowercase();
+            if (MIN_TOKEN_LENGTH..=MAX_TOKEN_LENGTH).contains(&word.chars().count()) {
+                tokens.insert(format!("{}{}", prefix, word));
+            }
+        }
+    }
+    if count_links(body) > 0 {
+        tokens.insert(LINK_TOKEN.to_string());
+    }
+    tokens
+}
+
+// naive Bayes over which words appear in a comment, trained on moderators' approve and spam
+// decisions. Kept with the comments, so everything stays on this server
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct Classifier {
+    spam: Class,
+    ham: Class,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct Class {
+    // comments learnt from
+    comments: u64,
+    // word -> comments it appeared in
+    tokens: HashMap<String, u64>,
+}
+
+impl Class {
+    fn learn(&mut self, tokens: &BTreeSet<String>) {
+        self.comments += 1;
+        for token in tokens {
+            *self.tokens.entry(token.clone()).or_default() += 1;
+        }
+    }
+
+    fn forget(&mut self, tokens: &BTreeSet<String>) {
+        self.comments = self.comments.saturating_sub(1);
+        for token in tokens {
+            if let Some(count) = self.tokens.get_mut(token) {
+                *count -= 1;
+                if *count == 0 {
+                    self.tokens.remove(token);
+                }
+            }
+        }
+    }
+
+    // log probability of a comment with these tokens being in the class, smoothed so unseen words
+    // don't rule it out
+    fn log_likelihood(&self, tokens: &BTreeSet<String>, comments: u64) -> f64 {
+        let prior = (self.comments as f64 + 1.0) / (comments as f64 + 2.0);
+        tokens.iter().fold(prior.ln(), |likelihood, token| {
+            let count = self.tokens.get(token).copied().unwrap_or_default();
+            likelihood + ((count as f64 + 1.0) / (self.comments as f64 + 2.0)).ln()
+        })
+    }
+}
+
+impl Classifier {
+    pub(crate) fn learn(&mut self, author: &str, body: &str, is_spam: bool) {
+        let tokens = tokens(author, body);
+        if is_spam { &mut self.spam } else { &mut self.ham }.learn(&tokens);
+    }
+
+    // undoes `learn`, for a moderator changing their mind or a comment being edited
+    pub(crate) fn forget(&mut self, author: &str, body: &str, is_spam: bool) {
+        let tokens = tokens(author, body);
+        if is_spam { &mut self.spam } else { &mut self.ham }.forget(&tokens);
+    }
+
+    // probability of a comment being spam, `None` until there are at least `min_samples` of both spam
+    // and approved comments to go on. Only words seen in training count
+    pub(crate) fn spam_probability(&self, author: &str, body: &str, min_samples: u64) -> Option<f64> {
+        if self.spam.comments < min_samples || self.ham.comments < min_samples {
+            return None;
+        }
+        let tokens: BTreeSet<String> = tokens(author, body)
+            .into_iter()
+            .filter(|token| self.spam.tokens.contains_key(token) || self.ham.tokens.contains_key(token))
+            .collect();
+        let comments = self.spam.comments + self.ham.comments;
+        let spam = self.spam.log_likelihood(&tokens, comments);
+        let ham = self.ham.log_likelihood(&tokens, comments);
+        Some(1.0 / (1.0 + (ham - spam).exp()))
+    }
+}
+
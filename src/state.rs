@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use crate::{
+    activitypub::ActivityPub,
+    analytics::Analytics,
+    comments::Comments,
+    config::Config,
+    content::Content,
+    http_client::{HttpClient, HyperClient},
+    page_cache::PageCache,
+    proof_of_work::ProofOfWork,
+    rate_limit::RateLimiters,
+    reactions::Reactions,
+    webmentions::Webmentions,
+};
+
+
+
+// everything a request needs, loaded together so a request only ever sees one consistent snapshot.
+// Replaced wholesale on reload, never mutated in place
+pub(crate) struct AppState {
+    pub(crate) config: Config,
+    pub(crate) content: Content,
+    // page cache revision this state renders for
+    pub(crate) revision: u64,
+    // rebuilt for each state, keeping the buckets of every group whose policy is unchanged, see `RateLimiters::new`
+    pub(crate) rate_limiters: RateLimiters,
+    // the rest are shared with the states before and after this one, see `AppState::reload` and for the
+    // page cache `PageCache::invalidate`
+    pub(crate) page_cache: Arc<PageCache>,
+    pub(crate) analytics: Arc<Analytics>,
+    pub(crate) comments: Arc<Comments>,
+    pub(crate) proof_of_work: Arc<ProofOfWork>,
+    pub(crate) reactions: Arc<Reactions>,
+    pub(crate) http_client: Arc<dyn HttpClient>,
+    pub(crate) webmentions: Arc<Webmentions>,
+    pub(crate) activitypub: Arc<ActivityPub>,
+}
+
+impl AppState {
+    pub(crate) fn load(config: Config) -> Result<Self, String> {
+        let http_client: Arc<dyn HttpClient> = HyperClient::new(&config.http_client)?;
+        Self::load_with_client(config, http_client)
+    }
+
+    // as `load`, making requests to other sites through `http_client`, e.g. a stub in tests
+    pub(crate) fn load_with_client(config: Config, http_client: Arc<dyn HttpClient>) -> Result<Self, String> {
+        let content = Content::load(&config.content.dir)?;
+        let analytics = Analytics::load(&config.analytics, &config.rankings)?;
+        let comments = Comments::load(&config.comments)?;
+        let reactions = Reactions::load(&config.reactions)?;
+        let webmentions = Webmentions::load(&config.webmention, http_client.clone())?;
+        let activitypub = ActivityPub::load(&config, http_client.clone())?;
+        let rate_limiters = RateLimiters::new(&config.rate_limit, None);
+        Ok(AppState {
+            config,
+            content,
+            page_cache: PageCache::new(),
+            revision: 0,
+            rate_limiters,
+            analytics,
+            comments,
+            proof_of_work: ProofOfWork::new(),
+            reactions,
+            http_client,
+            webmentions,
+            activitypub,
+        })
+    }
+
+    // the state to replace `current` with, keeping the cached pages the reload doesn't change
+    pub(crate) fn reload(config: Config, current: &AppState) -> Result<Self, String> {
+        let content = Content::load(&config.content.dir)?;
+        let revision = current.page_cache.invalidate(current, &config, &content);
+        let rate_limiters = RateLimiters::new(&config.rate_limit, Some(&current.rate_limiters));
+        Ok(AppState {
+            config,
+            content,
+            page_cache: current.page_cache.clone(),
+            revision,
+            rate_limiters,
+            analytics: current.analytics.clone(),
+            comments: current.comments.clone(),
+            proof_of_work: current.proof_of_work.clone(),
+            reactions: current.reactions.clone(),
+            http_client: current.http_client.clone(),
+            webmentions: current.webmentions.clone(),
+            activitypub: current.activitypub.clone(),
+        })
+    }
+
+    // tells other sites about the posts published or edited since the last time, in the background
+    pub(crate) fn announce(&self) {
+        self.webmentions.send_new(&self.config, &self.content);
+        self.activitypub.publish_new(&self.config, &self.content);
+    }
+}
@@ -0,0 +1,238 @@
+use std::{fmt, sync::OnceLock};
+
+use axum::http::{HeaderMap, HeaderName};
+use opentelemetry::{global, trace::TracerProvider, Context};
+use opentelemetry_http::HeaderExtractor;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use serde_json::{Map, Value};
+use tracing::Event;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::{
+    fmt::{
+        format::{JsonFields, Writer},
+        time::{FormatTime, SystemTime},
+        FmtContext, FormatEvent, FormatFields, FormattedFields,
+    },
+    registry::LookupSpan,
+};
+
+
+
+static TRACER_PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+// builds a layer exporting spans over OTLP/HTTP to the given collector traces endpoint,
+// e.g. http://localhost:4318/v1/traces. Spans are batched and sent from a background thread
+// so this can be installed before the tokio runtime is started.
+pub(crate) fn otlp_layer<S>(endpoint: &str) -> Result<OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>, String>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|err| format!("Failed to build OTLP exporter: {err}"))?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build(),
+        )
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+    let _ = TRACER_PROVIDER.set(provider);
+
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+// extracts the remote parent context from incoming W3C `traceparent`/`tracestate` headers.
+// Without an exporter installed the global propagator is a no-op and this returns an empty context
+pub(crate) fn extract_trace_context(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+// flush any batched spans, called once the server has shut down
+pub(crate) fn shutdown() {
+    if let Some(provider) = TRACER_PROVIDER.get() {
+        if let Err(err) = provider.shutdown() {
+            eprintln!("Failed to flush traces on shutdown. Error: {:#?}", err);
+        }
+    }
+}
+
+
+// JSON event formatter that lifts the fields of every span in scope to the top level of the log
+// line, so log aggregators can filter on e.g. `request_id` or `status_code` without digging
+// through a nested span list. Inner span fields take precedence over outer ones, and event fields
+// take precedence over both.
+pub(crate) struct FlattenedJson;
+
+impl<S> FormatEvent<S, JsonFields> for FlattenedJson
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut line = Map::new();
+
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        let metadata = event.metadata();
+        line.insert("timestamp".to_string(), Value::String(timestamp));
+        line.insert("level".to_string(), Value::String(metadata.level().to_string()));
+        line.insert("target".to_string(), Value::String(metadata.target().to_string()));
+
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                line.insert("span".to_string(), Value::String(span.name().to_string()));
+                let extensions = span.extensions();
+                // fields are stored pre-rendered as a JSON object by `JsonFields`
+                if let Some(fields) = extensions.get::<FormattedFields<JsonFields>>() {
+                    if let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(fields) {
+                        line.extend(fields);
+                    }
+                }
+            }
+        }
+
+        let mut fields = String::new();
+        ctx.format_fields(Writer::new(&mut fields), event)?;
+        if let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(&fields) {
+            line.extend(fields);
+        }
+
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+
+// renders headers for the `request_headers`/`response_headers` span fields, masking the values
+// of any header in the redaction list
+pub(crate) struct RedactedHeaders<'a> {
+    pub(crate) headers: &'a HeaderMap,
+    pub(crate) redact: &'a [HeaderName],
+}
+
+impl fmt::Debug for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (name, value) in self.headers {
+            if self.redact.contains(name) {
+                map.entry(&name.as_str(), &"[redacted]");
+            } else {
+                map.entry(&name.as_str(), &value.to_str().unwrap_or("[non-ascii]"));
+            }
+        }
+        map.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use axum::{body::Body, http::Request, routing::get, Router};
+    use tower::ServiceExt;
+    use tower_http::trace::TraceLayer;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    const TRACE_ID: &str = "0af7651916cd43dd8448eb211c80319c";
+
+    // an OTLP/HTTP collector that keeps the body of every export it is sent
+    fn otlp_receiver() -> (String, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let exports = Arc::new(Mutex::new(Vec::new()));
+        let received = exports.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                // requests one after another on the same connection, until it is closed
+                loop {
+                    let mut content_length = 0;
+                    let mut line = String::new();
+                    if stream.read_line(&mut line).unwrap_or(0) == 0 {
+                        break;
+                    }
+                    loop {
+                        line.clear();
+                        stream.read_line(&mut line).unwrap();
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = line.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                content_length = value.trim().parse().unwrap();
+                            }
+                        }
+                    }
+                    let mut body = vec![0; content_length];
+                    stream.read_exact(&mut body).unwrap();
+                    received.lock().unwrap().push(body);
+                    stream.get_mut().write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").unwrap();
+                }
+            }
+        });
+        (endpoint, exports)
+    }
+
+    #[test]
+    fn exports_request_spans_under_the_incoming_trace() {
+        let (endpoint, exports) = otlp_receiver();
+        let subscriber = tracing_subscriber::registry().with(otlp_layer(&endpoint).unwrap());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let app = Router::new().route("/", get(|| async { "ok" })).layer(
+                TraceLayer::new_for_http().make_span_with(|request: &Request<_>| {
+                    let span = tracing::info_span!("http_request", otel.kind = "server");
+                    span.set_parent(extract_trace_context(request.headers()));
+                    span
+                }),
+            );
+            let request = Request::get("/")
+                .header("traceparent", format!("00-{}-b7ad6b7169203331-01", TRACE_ID))
+                .body(Body::empty())
+                .unwrap();
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            runtime.block_on(app.oneshot(request)).unwrap();
+        });
+        TRACER_PROVIDER.get().unwrap().force_flush().unwrap();
+
+        // spans go out as protobuf, which has the name and trace id as they are
+        let trace_id: Vec<u8> = (0..TRACE_ID.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&TRACE_ID[i..i + 2], 16).unwrap())
+            .collect();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            let exported = exports.lock().unwrap().iter().any(|body| {
+                let contains = |needle: &[u8]| body.windows(needle.len()).any(|window| window == needle);
+                contains(b"http_request") && contains(&trace_id)
+            });
+            if exported {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("no span was exported");
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderName, Request, StatusCode},
+};
+use url::Url;
+
+use crate::{
+    config::Config,
+    http_client::{BoxFuture, Fetched, HttpClient},
+};
+
+
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+// fixtures under `testdata`, see testdata/README.md
+pub(crate) fn testdata(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+}
+
+// a directory of its own under the system temp dir, removed once dropped
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        let name = format!(
+            "{}-{}-{}",
+            env!("CARGO_PKG_NAME"),
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        // unwrap is fine as tests can't go on without somewhere to write
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub(crate) fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// a site at `https://musings.example` keeping its posts and everything it writes under `dir`
+pub(crate) fn config(dir: &TempDir) -> Config {
+    let mut config = Config::default();
+    config.server.public_url = Some("https://musings.example".to_string());
+    config.content.dir = dir.join("content");
+    config.analytics.path = dir.join("analytics.json");
+    config.comments.path = dir.join("comments.json");
+    config.reactions.path = dir.join("reactions.json");
+    config.reactions.key_path = dir.join("reactions.key");
+    config.webmention.path = dir.join("webmentions.json");
+    config.activitypub.path = dir.join("activitypub.json");
+    fs::create_dir_all(&config.content.dir).unwrap();
+    config
+}
+
+// publishes a post with the given markdown under `id`
+pub(crate) fn write_post(config: &Config, id: &str, markdown: &str) {
+    let post = format!("+++\ntitle = \"{}\"\ndate = 2024-05-01\n+++\n\n{}\n", id, markdown);
+    fs::write(config.content.dir.join(format!("{}.md", id)), post).unwrap();
+}
+
+// waits a few seconds at most for work done in the background, returning whether it got done
+pub(crate) async fn eventually(mut done: impl FnMut() -> bool) -> bool {
+    for _ in 0..100 {
+        if done() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    done()
+}
+
+// answers requests from canned responses by url rather than going out to the internet, keeping
+// every request it is sent. Urls without a response fail as if unreachable
+#[derive(Default)]
+pub(crate) struct StubClient {
+    responses: Mutex<HashMap<String, (StatusCode, HeaderMap, Bytes)>>,
+    requests: Mutex<Vec<Request<Bytes>>>,
+}
+
+impl StubClient {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(StubClient::default())
+    }
+
+    // answers every later request to `url` with this, in place of any response given before
+    pub(crate) fn respond(&self, url: &str, status: StatusCode, headers: &[(HeaderName, &str)], body: &str) {
+        let headers = headers.iter().map(|(name, value)| (name.clone(), value.parse().unwrap())).collect();
+        let url = Url::parse(url).unwrap().to_string();
+        self.responses.lock().unwrap().insert(url, (status, headers, Bytes::from(body.to_string())));
+    }
+
+    // the requests sent since the last time, oldest first
+    pub(crate) fn take_requests(&self) -> Vec<Request<Bytes>> {
+        std::mem::take(&mut *self.requests.lock().unwrap())
+    }
+}
+
+impl HttpClient for StubClient {
+    fn send(&self, request: Request<Bytes>) -> BoxFuture<'_, Result<Fetched, String>> {
+        let url = Url::parse(&request.uri().to_string()).unwrap();
+        let response = self.responses.lock().unwrap().get(url.as_str()).cloned();
+        self.requests.lock().unwrap().push(request);
+        Box::pin(async move {
+            let (status, headers, body) = response.ok_or_else(|| format!("{} is unreachable", url))?;
+            Ok(Fetched { status, headers, body, url })
+        })
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::Request,
+    http::{header, uri::Authority, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use notify::{Event, RecursiveMode, Watcher};
+use rustls::ServerConfig;
+use tokio::sync::mpsc;
+
+
+
+// renewals usually write the certificate and key one after the other, wait for both
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+// protocols offered during the TLS handshake, in order of preference
+pub(crate) fn alpn_protocols(http2: bool) -> Vec<Vec<u8>> {
+    let mut protocols = Vec::new();
+    if http2 {
+        protocols.push(b"h2".to_vec());
+    }
+    protocols.push(b"http/1.1".to_vec());
+    protocols
+}
+
+pub(crate) fn server_config(cert: &Path, key: &Path, alpn_protocols: Vec<Vec<u8>>) -> Result<ServerConfig, String> {
+    // only ring is compiled in, but rustls still needs telling which provider to use
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    let describe = |err: String| format!("failed to load certificate {} and key {}: {}", cert.display(), key.display(), err);
+
+    let certs = fs::File::open(cert)
+        .map_err(|err| err.to_string())
+        .and_then(|file| {
+            rustls_pemfile::certs(&mut BufReader::new(file))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|err| err.to_string())
+        })
+        .map_err(describe)?;
+    if certs.is_empty() {
+        return Err(describe(format!("no certificates found in {}", cert.display())));
+    }
+    let key_der = fs::File::open(key)
+        .map_err(|err| err.to_string())
+        .and_then(|file| rustls_pemfile::private_key(&mut BufReader::new(file)).map_err(|err| err.to_string()))
+        .map_err(describe)?
+        .ok_or_else(|| describe(format!("no private key found in {}", key.display())))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key_der)
+        .map_err(|err| describe(err.to_string()))?;
+    config.alpn_protocols = alpn_protocols;
+    Ok(config)
+}
+
+pub(crate) fn load(cert: &Path, key: &Path, http2: bool) -> Result<RustlsConfig, String> {
+    let config = server_config(cert, key, alpn_protocols(http2))?;
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+// calls `reload` whenever the certificate or key changes on disk. New connections pick up the new
+// certificate, a failed reload keeps serving the current one
+pub(crate) async fn watch_certificates<F>(cert: PathBuf, key: PathBuf, reload: F)
+where
+    F: Fn(&Path, &Path) -> Result<(), String> + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    let cert = fs::canonicalize(&cert).unwrap_or(cert);
+    let key = fs::canonicalize(&key).unwrap_or(key);
+
+    let (watched_cert, watched_key) = (cert.clone(), key.clone());
+    let watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        if !event.kind.is_access()
+            && event.paths.iter().any(|path| *path == watched_cert || *path == watched_key)
+        {
+            let _ = tx.send(());
+        }
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            tracing::error!("Failed to start certificate watcher, certificates won't be reloaded. Error: {:#?}", err);
+            return;
+        }
+    };
+    // parent directories are watched as renewals often replace the files, e.g. by symlink swaps
+    for dir in [cert.parent(), key.parent()].into_iter().flatten() {
+        if let Err(err) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+            tracing::error!("Failed to watch {}. Error: {:#?}", dir.display(), err);
+        }
+    }
+
+    while rx.recv().await.is_some() {
+        tokio::time::sleep(DEBOUNCE).await;
+        while rx.try_recv().is_ok() {}
+
+        match reload(&cert, &key) {
+            Ok(()) => tracing::info!("Reloaded TLS certificate {}.", cert.display()),
+            Err(err) => tracing::error!(
+                "Failed to reload TLS certificate, keeping the current one. Error: {}",
+                err
+            ),
+        }
+    }
+}
+
+
+// plain HTTP app that permanently redirects every request to the same path over HTTPS, on the host of
+// `server.public_url` when that is set so the client can't pick where it is sent
+pub(crate) fn redirect_router(https_port: u16, public_url: Option<&str>) -> Router {
+    let public_host = public_url
+        .and_then(|url| url.parse::<Uri>().ok())
+        .and_then(|url| url.authority().cloned());
+    Router::new().fallback(move |request: Request| {
+        let public_host = public_host.clone();
+        async move { redirect_to_https(request, https_port, public_host) }
+    })
+}
+
+fn redirect_to_https(request: Request, https_port: u16, public_host: Option<Authority>) -> Response {
+    let host = public_host.or_else(|| {
+        request
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<Authority>().ok())
+    });
+    let Some(host) = host else {
+        return (StatusCode::BAD_REQUEST, "Missing or invalid Host header").into_response();
+    };
+
+    let authority = match https_port {
+        443 => host.host().to_string(),
+        port => format!("{}:{}", host.host(), port),
+    };
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+
+    match Uri::builder()
+        .scheme("https")
+        .authority(authority)
+        .path_and_query(path_and_query)
+        .build()
+    {
+        Ok(uri) => Redirect::permanent(&uri.to_string()).into_response(),
+        Err(_) => (StatusCode::BAD_REQUEST, "Invalid request target").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+
+    use axum::{body::Body, routing::get};
+    use tower::ServiceExt;
+    use url::Url;
+
+    use super::*;
+    use crate::{
+        config::HttpClientConfig,
+        http_client::{self, HyperClient},
+        test_support::{testdata, TempDir},
+    };
+
+    // serves "hello" over HTTPS on a free port of localhost
+    fn serve(rustls_config: RustlsConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = Router::new().route("/", get(|| async { "hello" }));
+        tokio::spawn(axum_server::from_tcp_rustls(listener, rustls_config).serve(app.into_make_service()));
+        address
+    }
+
+    // GETs `/` trusting only `ca_file`
+    async fn fetch(address: SocketAddr, ca_file: &Path) -> Result<String, String> {
+        let client = HyperClient::new(&HttpClientConfig {
+            ca_file: Some(ca_file.to_path_buf()),
+            allow_private_addresses: true,
+            ..HttpClientConfig::default()
+        })?;
+        let url = Url::parse(&format!("https://localhost:{}/", address.port())).unwrap();
+        let fetched = http_client::get(&*client, &url, "text/plain").await?;
+        Ok(String::from_utf8_lossy(&fetched.body).into_owned())
+    }
+
+    #[tokio::test]
+    async fn serves_https_with_the_certificate() {
+        let rustls_config = load(&testdata("localhost-1.pem"), &testdata("localhost-1.key"), true).unwrap();
+        let address = serve(rustls_config);
+
+        assert_eq!(fetch(address, &testdata("localhost-1.pem")).await.unwrap(), "hello");
+        assert!(fetch(address, &testdata("localhost-2.pem")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reloads_the_certificate_when_it_changes() {
+        let dir = TempDir::new();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::copy(testdata("localhost-1.pem"), &cert).unwrap();
+        fs::copy(testdata("localhost-1.key"), &key).unwrap();
+        let rustls_config = load(&cert, &key, true).unwrap();
+        let address = serve(rustls_config.clone());
+        tokio::spawn(watch_certificates(cert.clone(), key.clone(), move |cert, key| {
+            rustls_config.reload_from_config(Arc::new(server_config(cert, key, alpn_protocols(true))?));
+            Ok(())
+        }));
+        // give the watcher time to start
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        fs::copy(testdata("localhost-2.pem"), &cert).unwrap();
+        fs::copy(testdata("localhost-2.key"), &key).unwrap();
+        for _ in 0..50 {
+            if fetch(address, &testdata("localhost-2.pem")).await.is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("the new certificate was never served");
+    }
+
+    async fn redirect(public_url: Option<&str>, host: &str, target: &str) -> Response {
+        let request = Request::get(target).header(header::HOST, host).body(Body::empty()).unwrap();
+        redirect_router(8443, public_url).oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn redirects_to_https_on_the_same_path() {
+        let response = redirect(None, "musings.example.com", "/posts/hello?page=2").await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[header::LOCATION], "https://musings.example.com:8443/posts/hello?page=2");
+    }
+
+    #[tokio::test]
+    async fn redirects_to_the_public_url_rather_than_the_host_header() {
+        let response = redirect(Some("https://musings.example.com"), "evil.example", "/").await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[header::LOCATION], "https://musings.example.com:8443/");
+    }
+}
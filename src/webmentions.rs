@@ -0,0 +1,674 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+};
+
+use askama::Template;
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Form,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use url::Url;
+
+use crate::{
+    analytics::write_atomically,
+    config::{Config, WebmentionConfig},
+    content::Content,
+    http_client::{self, Fetched, HttpClient},
+    metrics::metrics,
+    page_cache::POST_ROUTE,
+    services::handler_404,
+    state::AppState,
+};
+
+
+
+// titles longer than this are cut short under a post
+const MAX_TITLE_LENGTH: usize = 100;
+
+// everything kept in `webmention.path`
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct Stored {
+    // post id -> source url -> the verified mention
+    received: BTreeMap<String, BTreeMap<String, Mention>>,
+    // post id -> links in it mentions have been sent for. `None` until mentions are first sent, when
+    // every post already published is taken as sent so turning sending on doesn't mention the archive
+    sent: Option<BTreeMap<String, BTreeSet<String>>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Mention {
+    source: String,
+    // of the source page, if it has one
+    title: Option<String>,
+    verified: DateTime<Utc>,
+}
+
+impl Mention {
+    fn label(&self) -> &str {
+        self.title.as_deref().unwrap_or(&self.source)
+    }
+
+    fn host(&self) -> String {
+        Url::parse(&self.source)
+            .ok()
+            .and_then(|source| source.host_str().map(str::to_string))
+            .unwrap_or_default()
+    }
+}
+
+// a mention waiting to be verified
+struct Queued {
+    source: Url,
+    target: Url,
+    post: String,
+}
+
+// webmentions, see https://www.w3.org/TR/webmention/. Mentions of a post are queued and shown once the
+// source has been fetched and found to link to it. Mentions are sent for the links in a post when it
+// is published or edited. Shared by every state so mentions survive reloads
+pub(crate) struct Webmentions {
+    path: PathBuf,
+    client: Arc<dyn HttpClient>,
+    stored: Mutex<Stored>,
+    queue: mpsc::Sender<Queued>,
+}
+
+impl Webmentions {
+    pub(crate) fn load(config: &WebmentionConfig, client: Arc<dyn HttpClient>) -> Result<Arc<Self>, String> {
+        let stored: Stored = match fs::read(&config.path) {
+            Ok(raw) => serde_json::from_slice(&raw)
+                .map_err(|err| format!("failed to read webmentions from {}: {}", config.path.display(), err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Stored::default(),
+            Err(err) => return Err(format!("failed to read webmentions from {}: {}", config.path.display(), err)),
+        };
+
+        let (queue, queued) = mpsc::channel(config.queue_size);
+        let webmentions = Arc::new(Webmentions {
+            path: config.path.clone(),
+            client,
+            stored: Mutex::new(stored),
+            queue,
+        });
+        tokio::spawn(verify_queued(Arc::downgrade(&webmentions), queued));
+        Ok(webmentions)
+    }
+
+    // verified mentions of a post, newest first
+    fn received(&self, post: &str) -> Vec<Mention> {
+        let stored = self.stored.lock().unwrap();
+        let mut mentions: Vec<Mention> = stored
+            .received
+            .get(post)
+            .into_iter()
+            .flat_map(|mentions| mentions.values().cloned())
+            .collect();
+        mentions.sort_by_key(|mention| Reverse(mention.verified));
+        mentions
+    }
+
+    // shows the mention if its source links to the post, or takes it down if the source no longer
+    // does, see https://www.w3.org/TR/webmention/#h-updating-existing-webmentions
+    async fn verify(self: &Arc<Self>, queued: Queued) {
+        let mention = match http_client::get(&*self.client, &queued.source, "text/html").await {
+            Ok(fetched) if fetched.status.is_success() => links_to(&fetched, &queued.target).then(|| Mention {
+                source: queued.source.to_string(),
+                title: title(&fetched),
+                verified: Utc::now(),
+            }),
+            Ok(fetched) if fetched.status == StatusCode::GONE => None,
+            Ok(fetched) => {
+                let (post, source) = (&queued.post, &queued.source);
+                tracing::debug!("Mention of {} not verified, {} answered {}.", post, source, fetched.status);
+                metrics().webmentions.with_label_values(&["unreachable"]).inc();
+                return;
+            }
+            Err(err) => {
+                tracing::debug!("Mention of {} not verified. Error: {}", queued.post, err);
+                metrics().webmentions.with_label_values(&["unreachable"]).inc();
+                return;
+            }
+        };
+
+        let event = {
+            let mut stored = self.stored.lock().unwrap();
+            let mentions = stored.received.entry(queued.post.clone()).or_default();
+            let event = match mention {
+                Some(mention) => {
+                    mentions.insert(mention.source.clone(), mention);
+                    "verified"
+                }
+                None if mentions.remove(queued.source.as_str()).is_some() => "removed",
+                None => "rejected",
+            };
+            if mentions.is_empty() {
+                stored.received.remove(&queued.post);
+            }
+            event
+        };
+        tracing::info!("Mention of {} from {} {}.", queued.post, queued.source, event);
+        metrics().webmentions.with_label_values(&[event]).inc();
+        if event != "rejected" {
+            persist(self).await;
+        }
+    }
+
+    // sends mentions for the links in posts that haven't had one yet, in the background. A link that
+    // couldn't be reached is tried again on the next reload
+    pub(crate) fn send_new(self: &Arc<Self>, config: &Config, content: &Content) {
+        if !config.webmention.send || config.server.public_url.is_none() {
+            return;
+        }
+
+        let origin = config.public_origin();
+        let mut pending = Vec::new();
+        let is_first = {
+            let mut stored = self.stored.lock().unwrap();
+            let is_first = stored.sent.is_none();
+            let sent = stored.sent.get_or_insert_with(BTreeMap::new);
+            for post in content.posts() {
+                let post_sent = sent.entry(post.id.clone()).or_default();
+                for target in outgoing_links(&post.content, &origin) {
+                    if post_sent.insert(target.to_string()) && !is_first {
+                        pending.push((post.id.clone(), config.post_url(&post.id), target));
+                    }
+                }
+            }
+            is_first
+        };
+        if pending.is_empty() && !is_first {
+            return;
+        }
+
+        let webmentions = self.clone();
+        tokio::spawn(async move {
+            for (post, source, target) in pending {
+                match send(&*webmentions.client, &source, &target).await {
+                    Ok(Some(endpoint)) => {
+                        tracing::info!("Sent a mention of {} from {} to {}.", target, post, endpoint);
+                        metrics().webmentions.with_label_values(&["sent"]).inc();
+                    }
+                    Ok(None) => tracing::debug!("{} doesn't accept webmentions.", target),
+                    Err(err) => {
+                        tracing::warn!("Failed to send a mention of {} from {}. Error: {}", target, post, err);
+                        metrics().webmentions.with_label_values(&["send_failed"]).inc();
+                        let mut stored = webmentions.stored.lock().unwrap();
+                        if let Some(post_sent) = stored.sent.as_mut().and_then(|sent| sent.get_mut(&post)) {
+                            post_sent.remove(target.as_str());
+                        }
+                    }
+                }
+            }
+            persist(&webmentions).await;
+        });
+    }
+
+    fn save(&self) {
+        // unwrap is fine as mentions are plain strings and dates
+        let raw = serde_json::to_vec(&*self.stored.lock().unwrap()).unwrap();
+        if let Err(err) = write_atomically(&self.path, &raw) {
+            tracing::error!("Failed to write webmentions to {}. Error: {:#?}", self.path.display(), err);
+        }
+    }
+}
+
+// one at a time, so a burst of mentions can't have the server fetching from everywhere at once
+async fn verify_queued(webmentions: Weak<Webmentions>, mut queued: mpsc::Receiver<Queued>) {
+    while let Some(mention) = queued.recv().await {
+        let Some(webmentions) = webmentions.upgrade() else {
+            return;
+        };
+        webmentions.verify(mention).await;
+    }
+}
+
+// writes the webmentions without holding up the runtime
+async fn persist(webmentions: &Arc<Webmentions>) {
+    let webmentions = webmentions.clone();
+    if let Err(err) = tokio::task::spawn_blocking(move || webmentions.save()).await {
+        tracing::error!("Failed to write webmentions. Error: {:#?}", err);
+    }
+}
+
+// sends a mention of `target` from `source`, returning the endpoint it went to or `None` when the
+// target doesn't take mentions
+async fn send(client: &dyn HttpClient, source: &str, target: &Url) -> Result<Option<Url>, String> {
+    let Some(endpoint) = discover_endpoint(client, target).await? else {
+        return Ok(None);
+    };
+    let fetched = http_client::post_form(client, &endpoint, &[("source", source), ("target", target.as_str())]).await?;
+    if !fetched.status.is_success() {
+        return Err(format!("{} answered {}", endpoint, fetched.status));
+    }
+    Ok(Some(endpoint))
+}
+
+// the first endpoint given in a `Link` header, then in a `<link>` or `<a>` element, see
+// https://www.w3.org/TR/webmention/#sender-discovers-receiver-webmention-endpoint
+async fn discover_endpoint(client: &dyn HttpClient, target: &Url) -> Result<Option<Url>, String> {
+    let fetched = http_client::get(client, target, "text/html").await?;
+    if !fetched.status.is_success() {
+        return Ok(None);
+    }
+
+    let from_header = fetched
+        .headers
+        .get_all(header::LINK)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|link| {
+            let (url, params) = link.split_once(';')?;
+            let url = url.trim().strip_prefix('<')?.strip_suffix('>')?;
+            let rel = params.split(';').find_map(|param| param.trim().strip_prefix("rel="))?;
+            is_webmention_rel(rel.trim_matches('"')).then(|| url.to_string())
+        });
+    let from_html = || {
+        let html = String::from_utf8_lossy(&fetched.body);
+        start_tags(&html)
+            .into_iter()
+            .filter(|tag| tag.name == "link" || tag.name == "a")
+            .find(|tag| tag.attribute("rel").is_some_and(is_webmention_rel))
+            .and_then(|tag| tag.attribute("href").map(str::to_string))
+    };
+    let endpoint = match from_header {
+        Some(endpoint) => endpoint,
+        None if fetched.is_html() => match from_html() {
+            Some(endpoint) => endpoint,
+            None => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+
+    // relative to the page, an empty href being the page itself
+    let endpoint = fetched
+        .url
+        .join(&endpoint)
+        .map_err(|err| format!("{} gave a bad endpoint '{}': {}", target, endpoint, err))?;
+    Ok(matches!(endpoint.scheme(), "http" | "https").then_some(endpoint))
+}
+
+fn is_webmention_rel(rel: &str) -> bool {
+    rel.split_whitespace().any(|rel| rel.eq_ignore_ascii_case("webmention"))
+}
+
+// links from a post's html to other sites
+fn outgoing_links(html: &str, origin: &str) -> BTreeSet<Url> {
+    start_tags(html)
+        .into_iter()
+        .filter(|tag| tag.name == "a")
+        .filter_map(|tag| Url::parse(tag.attribute("href")?).ok())
+        .filter(|url| matches!(url.scheme(), "http" | "https") && url.origin().ascii_serialization() != origin)
+        .map(|mut url| {
+            url.set_fragment(None);
+            url
+        })
+        .collect()
+}
+
+// whether a source links to the target exactly, as an attribute of html or anywhere in anything else
+fn links_to(fetched: &Fetched, target: &Url) -> bool {
+    let body = String::from_utf8_lossy(&fetched.body);
+    if !fetched.is_html() {
+        return body.contains(target.as_str());
+    }
+    start_tags(&body).iter().any(|tag| {
+        tag.attributes
+            .iter()
+            .any(|(name, value)| (name == "href" || name == "src") && value == target.as_str())
+    })
+}
+
+fn title(fetched: &Fetched) -> Option<String> {
+    if !fetched.is_html() {
+        return None;
+    }
+    let html = String::from_utf8_lossy(&fetched.body);
+    // lowercasing ascii leaves the byte offsets as they are
+    let lowercase = html.to_ascii_lowercase();
+    let start = lowercase.find("<title")?;
+    let start = start + lowercase[start..].find('>')? + 1;
+    let end = start + lowercase[start..].find("</title")?;
+    let title = decode_entities(&html[start..end]).split_whitespace().collect::<Vec<_>>().join(" ");
+    if title.is_empty() {
+        return None;
+    }
+    Some(match title.char_indices().nth(MAX_TITLE_LENGTH) {
+        Some((end, _)) => format!("{}…", &title[..end]),
+        None => title,
+    })
+}
+
+// an html start tag and its attributes
+struct Tag {
+    name: String,
+    attributes: Vec<(String, String)>,
+}
+
+impl Tag {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+// the start tags of an html document, enough to find links without a full parser. Tags in comments
+// and scripts are found too, which at worst counts a link the page doesn't show
+fn start_tags(html: &str) -> Vec<Tag> {
+    let mut tags = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let name_end = rest.find(|c: char| c.is_whitespace() || c == '>' || c == '/').unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = &rest[name_end..];
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            continue;
+        }
+
+        let mut attributes = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+            if rest.is_empty() || rest.starts_with('>') {
+                break;
+            }
+            let key_end = rest
+                .find(|c: char| c.is_whitespace() || c == '=' || c == '>' || c == '/')
+                .unwrap_or(rest.len());
+            if key_end == 0 {
+                // a stray `=`
+                rest = &rest[1..];
+                continue;
+            }
+            let key = rest[..key_end].to_ascii_lowercase();
+            rest = rest[key_end..].trim_start();
+
+            let mut value = String::new();
+            if let Some(after) = rest.strip_prefix('=') {
+                let after = after.trim_start();
+                let (raw, remaining) = match after.chars().next() {
+                    Some(quote @ ('"' | '\'')) => {
+                        let quoted = &after[1..];
+                        let end = quoted.find(quote).unwrap_or(quoted.len());
+                        (&quoted[..end], quoted.get(end + 1..).unwrap_or_default())
+                    }
+                    _ => {
+                        let end = after.find(|c: char| c.is_whitespace() || c == '>').unwrap_or(after.len());
+                        (&after[..end], &after[end..])
+                    }
+                };
+                value = decode_entities(raw);
+                rest = remaining;
+            }
+            attributes.push((key, value));
+        }
+        tags.push(Tag { name, attributes });
+    }
+    tags
+}
+
+// the entities likely in a url or title, `&amp;` last so it doesn't make new ones
+fn decode_entities(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Webmention {
+    source: String,
+    target: String,
+}
+
+// the webmention endpoint. Mentions are checked in the background, so this only checks the target is
+// one of our posts, see https://www.w3.org/TR/webmention/#receiving-webmentions
+pub(crate) async fn receive_webmention(State(state): State<Arc<AppState>>, Form(form): Form<Webmention>) -> Response {
+    if !state.config.webmention.enabled {
+        return handler_404().await.into_response();
+    }
+
+    let parse = |url: &str| Url::parse(url).ok().filter(|url| matches!(url.scheme(), "http" | "https"));
+    let (Some(source), Some(mut target)) = (parse(&form.source), parse(&form.target)) else {
+        return (StatusCode::BAD_REQUEST, "source and target must be absolute http(s) urls").into_response();
+    };
+    target.set_fragment(None);
+    if source.as_str() == target.as_str() {
+        return (StatusCode::BAD_REQUEST, "source and target must differ").into_response();
+    }
+    let post_prefix = format!("{}{}/", state.config.public_origin(), POST_ROUTE);
+    let post = target
+        .as_str()
+        .strip_prefix(&post_prefix)
+        .and_then(|id| state.content.get(id))
+        .filter(|post| state.config.post_url(&post.id) == target.as_str());
+    let Some(post) = post else {
+        return (StatusCode::BAD_REQUEST, "target is not a post on this site").into_response();
+    };
+
+    let queued = Queued {
+        source,
+        target,
+        post: post.id.clone(),
+    };
+    if state.webmentions.queue.try_send(queued).is_err() {
+        metrics().webmentions.with_label_values(&["turned_away"]).inc();
+        let message = "Too many mentions waiting, please try again later.";
+        let mut response = (StatusCode::SERVICE_UNAVAILABLE, message).into_response();
+        response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from_static("60"));
+        return response;
+    }
+    metrics().webmentions.with_label_values(&["received"]).inc();
+    let message = "Accepted, the mention will show once the source is found to link to the post.";
+    (StatusCode::ACCEPTED, message).into_response()
+}
+
+#[derive(Template)]
+#[template(path = "webmentions.html")]
+struct WebmentionsTemplate {
+    mentions: Vec<Mention>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WebmentionsParams {
+    post: String,
+}
+
+// htmx fragment with the verified mentions of a post, empty when there are none or webmentions are
+// disabled
+pub(crate) async fn get_webmentions(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<WebmentionsParams>,
+) -> Response {
+    if !state.config.webmention.enabled {
+        return Html(String::new()).into_response();
+    }
+    if state.content.get(&params.post).is_none() {
+        return handler_404().await.into_response();
+    }
+
+    let mentions = state.webmentions.received(&params.post);
+    Html(WebmentionsTemplate { mentions }.render().unwrap()).into_response()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Bytes, http::Method};
+
+    use super::*;
+    use crate::test_support::{config, eventually, write_post, StubClient, TempDir};
+
+    const POST: &str = "https://musings.example/posts/hello";
+    const SOURCE: &str = "https://other.example/reply";
+
+    fn state(dir: &TempDir, client: Arc<StubClient>) -> Arc<AppState> {
+        let mut config = config(dir);
+        config.webmention.send = false;
+        write_post(&config, "hello", "Hello world");
+        Arc::new(AppState::load_with_client(config, client).unwrap())
+    }
+
+    async fn receive(state: &Arc<AppState>, source: &str, target: &str) -> StatusCode {
+        let form = Webmention {
+            source: source.to_string(),
+            target: target.to_string(),
+        };
+        receive_webmention(State(state.clone()), Form(form)).await.status()
+    }
+
+    fn queued(source: &str) -> Queued {
+        Queued {
+            source: Url::parse(source).unwrap(),
+            target: Url::parse(POST).unwrap(),
+            post: "hello".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn shows_mentions_once_the_source_links_to_the_post() {
+        let dir = TempDir::new();
+        let client = StubClient::new();
+        let state = state(&dir, client.clone());
+        let page = format!("<title>A &amp; reply</title><p>Replying to <a href=\"{}\">this</a></p>", POST);
+        client.respond(SOURCE, StatusCode::OK, &[(header::CONTENT_TYPE, "text/html")], &page);
+
+        assert_eq!(receive(&state, SOURCE, &format!("{}#comments", POST)).await, StatusCode::ACCEPTED);
+        assert!(eventually(|| !state.webmentions.received("hello").is_empty()).await);
+        let mentions = state.webmentions.received("hello");
+        assert_eq!(mentions[0].source, SOURCE);
+        assert_eq!(mentions[0].label(), "A & reply");
+
+        // a source that has gone takes its mention with it
+        client.respond(SOURCE, StatusCode::GONE, &[], "");
+        assert_eq!(receive(&state, SOURCE, POST).await, StatusCode::ACCEPTED);
+        assert!(eventually(|| state.webmentions.received("hello").is_empty()).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_sources_that_dont_link_to_the_post() {
+        let dir = TempDir::new();
+        let client = StubClient::new();
+        let state = state(&dir, client.clone());
+        let page = "<a href=\"https://musings.example/posts/hello-again\">close but not quite</a>";
+        client.respond(SOURCE, StatusCode::OK, &[(header::CONTENT_TYPE, "text/html")], page);
+
+        state.webmentions.verify(queued(SOURCE)).await;
+        assert!(state.webmentions.received("hello").is_empty());
+        // nor are sources that can't be reached
+        state.webmentions.verify(queued("https://unreachable.example/")).await;
+        assert!(state.webmentions.received("hello").is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_takes_mentions_of_posts() {
+        let dir = TempDir::new();
+        let state = state(&dir, StubClient::new());
+
+        for target in [
+            "https://musings.example/posts/no-such-post",
+            "https://musings.example/",
+            "https://elsewhere.example/posts/hello",
+            "ftp://musings.example/posts/hello",
+        ] {
+            assert_eq!(receive(&state, SOURCE, target).await, StatusCode::BAD_REQUEST, "{}", target);
+        }
+        assert_eq!(receive(&state, POST, POST).await, StatusCode::BAD_REQUEST);
+        assert_eq!(receive(&state, "not a url", POST).await, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn discovers_endpoints() {
+        let client = StubClient::new();
+        let html = [(header::CONTENT_TYPE, "text/html; charset=utf-8")];
+        let discover = |url: &str| {
+            let client = client.clone();
+            let url = Url::parse(url).unwrap();
+            async move { discover_endpoint(&*client, &url).await.unwrap().map(|url| url.to_string()) }
+        };
+
+        // a `Link` header comes before the html, relative to the page
+        let link = [(header::LINK, "<https://a.example/other>; rel=\"other\", </mention>; rel=\"webmention\"")];
+        client.respond("https://a.example/post", StatusCode::OK, &link, "<link rel=\"webmention\" href=\"/not-this\">");
+        assert_eq!(discover("https://a.example/post").await.as_deref(), Some("https://a.example/mention"));
+
+        let page = "<head><LINK REL=\"stylesheet webmention\" HREF='endpoint?x=1&amp;y=2'></head>";
+        client.respond("https://b.example/posts/1", StatusCode::OK, &html, page);
+        let endpoint = discover("https://b.example/posts/1").await;
+        assert_eq!(endpoint.as_deref(), Some("https://b.example/posts/endpoint?x=1&y=2"));
+
+        // an empty href is the page itself
+        client.respond("https://c.example/page", StatusCode::OK, &html, "<a rel=webmention href=\"\">mention</a>");
+        assert_eq!(discover("https://c.example/page").await.as_deref(), Some("https://c.example/page"));
+
+        // redirects are followed, and the endpoint is relative to where they end up
+        let to_d = [(header::LOCATION, "https://d.example/moved")];
+        client.respond("https://c.example/old", StatusCode::MOVED_PERMANENTLY, &to_d, "");
+        client.respond("https://d.example/moved", StatusCode::OK, &html, "<link rel=webmention href=/wm>");
+        assert_eq!(discover("https://c.example/old").await.as_deref(), Some("https://d.example/wm"));
+
+        client.respond("https://e.example/", StatusCode::OK, &html, "<a href=\"/wm\">no rel</a>");
+        assert_eq!(discover("https://e.example/").await, None);
+        client.respond("https://e.example/json", StatusCode::OK, &[(header::CONTENT_TYPE, "application/json")], "");
+        assert_eq!(discover("https://e.example/json").await, None);
+        client.respond("https://e.example/js", StatusCode::OK, &html, "<link rel=webmention href=\"javascript:x\">");
+        assert_eq!(discover("https://e.example/js").await, None);
+        assert!(discover_endpoint(&*client, &Url::parse("https://unreachable.example/").unwrap()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sends_mentions_for_links_in_new_posts() {
+        let dir = TempDir::new();
+        let client = StubClient::new();
+        let mut config = config(&dir);
+        config.webmention.send = true;
+        write_post(&config, "old", "[an old link](https://old.example/)");
+        let state = AppState::load_with_client(config.clone(), client.clone()).unwrap();
+        // posts from before sending was turned on are left alone
+        state.webmentions.send_new(&config, &state.content);
+
+        let target = "https://other.example/article";
+        let endpoint = [(header::LINK, "</webmention>; rel=\"webmention\"")];
+        client.respond(target, StatusCode::OK, &endpoint, "");
+        client.respond("https://other.example/webmention", StatusCode::ACCEPTED, &[], "");
+        let markdown = format!("[a link]({}#part) and [one of ours](https://musings.example/posts/old)", target);
+        write_post(&config, "new", &markdown);
+        let content = Content::load(&config.content.dir).unwrap();
+        state.webmentions.send_new(&config, &content);
+
+        let mut requests: Vec<(Method, String, Bytes)> = Vec::new();
+        let sent = eventually(|| {
+            let taken = client.take_requests().into_iter().map(|request| {
+                let (parts, body) = request.into_parts();
+                (parts.method, parts.uri.to_string(), body)
+            });
+            requests.extend(taken);
+            requests.len() >= 2
+        })
+        .await;
+        assert!(sent);
+        assert_eq!(requests[0].0, Method::GET);
+        assert_eq!(requests[0].1, target);
+        assert_eq!(requests[1].0, Method::POST);
+        assert_eq!(requests[1].1, "https://other.example/webmention");
+        let form: Vec<(String, String)> = url::form_urlencoded::parse(&requests[1].2).into_owned().collect();
+        let expected = [("source", "https://musings.example/posts/new"), ("target", target)]
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        assert_eq!(form, expected);
+
+        // each link is only mentioned the once
+        state.webmentions.send_new(&config, &content);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(client.take_requests().is_empty());
+    }
+}
@@ -0,0 +1,83 @@
+// solves a proof of work challenge before htmx sends a request from an element marked `data-pow`,
+// see src/proof_of_work.rs
+(function () {
+  const encoder = new TextEncoder();
+
+  function leadingZeroBits(hash) {
+    let bits = 0;
+    for (const byte of hash) {
+      if (byte !== 0) {
+        return bits + Math.clz32(byte) - 24;
+      }
+      bits += 8;
+    }
+    return bits;
+  }
+
+  async function solve(challenge, difficulty) {
+    for (let counter = 0; ; counter++) {
+      const solution = challenge + ":" + counter;
+      const hash = await crypto.subtle.digest("SHA-256", encoder.encode(solution));
+      if (leadingZeroBits(new Uint8Array(hash)) >= difficulty) {
+        return solution;
+      }
+    }
+  }
+
+  // the buttons are disabled while solving so the form isn't sent twice. They must be enabled again
+  // before the request is issued, htmx leaves out the value of a disabled button
+  function setBusy(elt, busy) {
+    for (const button of elt.querySelectorAll("button")) {
+      button.disabled = busy;
+    }
+  }
+
+  // shown at the top of the form, in place of any from an earlier attempt
+  function showError(elt, message) {
+    let alert = elt.querySelector("[data-pow-error]");
+    if (alert === null) {
+      alert = document.createElement("p");
+      alert.setAttribute("role", "alert");
+      alert.setAttribute("data-pow-error", "");
+      elt.prepend(alert);
+    }
+    alert.textContent = message;
+  }
+
+  // holds the request back until the challenge for its path is solved
+  document.addEventListener("htmx:confirm", function (event) {
+    const elt = event.detail.elt;
+    if (!elt.hasAttribute("data-pow") || elt.powSolution !== undefined) {
+      return;
+    }
+    event.preventDefault();
+    setBusy(elt, true);
+    fetch("/api/pow/challenge?path=" + encodeURIComponent(event.detail.path.split("?")[0]))
+      .then((response) => {
+        if (!response.ok) {
+          throw new Error("the challenge was refused with " + response.status);
+        }
+        return response.json();
+      })
+      .then((issued) => (issued.enabled ? solve(issued.challenge, issued.difficulty) : null))
+      .then((solution) => {
+        setBusy(elt, false);
+        elt.querySelector("[data-pow-error]")?.remove();
+        elt.powSolution = solution;
+        event.detail.issueRequest(true);
+      })
+      .catch((error) => {
+        console.error("Failed to solve a proof of work challenge.", error);
+        setBusy(elt, false);
+        showError(elt, "Sorry, that couldn't be sent. Please wait a moment and try again.");
+      });
+  });
+
+  document.addEventListener("htmx:configRequest", function (event) {
+    const elt = event.detail.elt;
+    if (elt.powSolution) {
+      event.detail.headers["X-Proof-Of-Work"] = elt.powSolution;
+    }
+    delete elt.powSolution;
+  });
+})();